@@ -0,0 +1,171 @@
+use std::{fs::File, io::Write};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::{
+    client::{ClientID, SerializableClient},
+    error::Error,
+    transaction::{Currency, ParsedTransaction, TransactionID, TransactionType},
+};
+
+/// Effect of a processed transaction: its outcome, and the state of the client after it.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    pub client: ClientID,
+    pub tx: TransactionID,
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    pub amount: Option<Decimal>,
+    /// Currency the transaction moved funds in, which the balances are in
+    pub currency: Option<Currency>,
+    /// Currency a conversion credited, and the rate it was made at
+    pub to_currency: Option<Currency>,
+    pub rate: Option<Decimal>,
+    /// `applied` or `rejected`
+    pub status: &'static str,
+    pub error: Option<String>,
+    // Empty if the client doesn't exist (eg. its first transaction was rejected)
+    pub available: Option<Decimal>,
+    pub held: Option<Decimal>,
+    pub locked: Option<bool>,
+}
+
+impl AuditRecord {
+    pub fn new<T>(
+        tx: &ParsedTransaction,
+        result: &Result<T, Error>,
+        account: Option<SerializableClient>,
+        rate: Option<Decimal>,
+    ) -> AuditRecord {
+        AuditRecord {
+            client: tx.client_id,
+            tx: tx.tx_id,
+            tx_type: tx.tx_type.clone(),
+            amount: tx.amount,
+            currency: account
+                .as_ref()
+                .map(|account| account.currency.clone())
+                .or_else(|| tx.currency.clone()),
+            to_currency: tx.to_currency.clone(),
+            rate,
+            status: if result.is_ok() {
+                "applied"
+            } else {
+                "rejected"
+            },
+            error: result.as_ref().err().map(|e| format!("{:?}", e)),
+            available: account.as_ref().map(|account| account.available),
+            held: account.as_ref().map(|account| account.held),
+            locked: account.map(|account| account.locked),
+        }
+    }
+
+    /// Record of the recipient of the transfer `tx`, with its state right after it.
+    pub fn recipient(tx: &ParsedTransaction, account: SerializableClient) -> AuditRecord {
+        AuditRecord {
+            client: account.client,
+            ..AuditRecord::new(tx, &Ok::<(), Error>(()), Some(account), None)
+        }
+    }
+}
+
+/// Shared csv sink for audit records, written to by all processors.
+pub struct AuditWriter {
+    writer: Mutex<csv::Writer<Box<dyn Write + Send>>>,
+}
+
+impl AuditWriter {
+    pub fn new(writer: Box<dyn Write + Send>) -> AuditWriter {
+        AuditWriter {
+            writer: Mutex::new(csv::Writer::from_writer(writer)),
+        }
+    }
+
+    pub fn from_path(path: &str) -> Result<AuditWriter, Error> {
+        let file = File::create(path).map_err(|_| Error::UnknownFile)?;
+        Ok(AuditWriter::new(Box::new(file)))
+    }
+
+    pub async fn record(&self, record: AuditRecord) {
+        if let Err(e) = self.writer.lock().await.serialize(&record) {
+            debug!("Failed writing audit record {:?}: {:?}", record, e);
+        }
+    }
+
+    pub async fn flush(&self) {
+        if let Err(e) = self.writer.lock().await.flush() {
+            debug!("Failed flushing audit records: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ledger::Ledger, rates::RateTable, source::CsvSource};
+    use std::sync::{Arc, Mutex as StdMutex};
+
+    // Shared buffer, so the output can be read once the writer is owned by the ledger
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<StdMutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit() {
+        let input = "type,client,tx,amount,currency,to_currency,timestamp,to
+deposit,1,1,10,eur,,,
+withdrawal,1,2,11,EUR,,,
+dispute,1,1,4,,,,
+deposit,2,1,1,,,,
+chargeback,1,1,,,,,
+deposit,1,3,1,USD,,,
+deposit,3,4,10,EUR,,,
+convert,3,5,4,EUR,USD,100,
+convert,3,6,4,EUR,GBP,100,
+convert,3,10,4,EUR,USD,,
+deposit,4,7,1,EUR,,,
+transfer,3,8,2,EUR,,,4
+transfer,3,9,20,EUR,,,4
+";
+        let buffer = Buffer::default();
+        let ledger = Ledger::new()
+            .with_audit(AuditWriter::new(Box::new(buffer.clone())))
+            .with_rates(RateTable::new().with_rate("EUR", "USD", Decimal::new(108, 2), 0));
+        ledger
+            .replay_source(Box::new(CsvSource::new(input.as_bytes()).unwrap()))
+            .await
+            .unwrap();
+
+        let audit = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            audit,
+            "client,tx,type,amount,currency,to_currency,rate,status,error,available,held,locked
+1,1,deposit,10,EUR,,,applied,,10,0.0000,false
+1,2,withdrawal,11,EUR,,,rejected,NoAvailableFunds,10,0.0000,false
+1,1,dispute,4,EUR,,,applied,,6,4,false
+2,1,deposit,1,,,,rejected,DuplicateTransaction,,,
+1,1,chargeback,,EUR,,,applied,,6,0.0000,true
+1,3,deposit,1,USD,,,rejected,AccountLocked,0.0000,0.0000,true
+3,4,deposit,10,EUR,,,applied,,10,0.0000,false
+3,5,convert,4,EUR,USD,1.08,applied,,6,0.0000,false
+3,6,convert,4,EUR,GBP,,rejected,UnknownRate,6,0.0000,false
+3,10,convert,4,EUR,USD,,rejected,MissingTimestamp,6,0.0000,false
+4,7,deposit,1,EUR,,,applied,,1,0.0000,false
+3,8,transfer,2,EUR,,,applied,,4,0.0000,false
+4,8,transfer,2,EUR,,,applied,,3,0.0000,false
+3,9,transfer,20,EUR,,,rejected,NoAvailableFunds,4,0.0000,false
+"
+        );
+    }
+}
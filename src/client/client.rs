@@ -1,62 +1,385 @@
+use crate::client::policy::{ConversionPolicy, DisputePolicy, Policy};
 use crate::error::Error;
+use crate::journal::{Account, JournalEntry, Totals, TrialBalance};
 use crate::transaction::{
-    Transaction, TransactionID, TransactionType, TransactionsMap, UniqueTransactionIDs,
+    AdminAction, Currency, DisputeState, DisputesMap, Transaction, TransactionID, TransactionType,
+    TransactionsMap, UniqueTransactionIDs, NO_CURRENCY,
 };
 use rust_decimal::Decimal;
-use serde::{Serialize, Serializer};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 
 pub type ClientID = u16;
+pub type BalancesMap = BTreeMap<Currency, Balance>;
+pub type FeesMap = BTreeMap<Currency, Decimal>;
 
-#[derive(Debug)]
-pub struct Client {
-    pub id: ClientID,
+/// Funds of a client in one currency.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Balance {
     pub available: Decimal,
     pub held: Decimal,
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub id: ClientID,
     pub locked: bool,
+    // Position of the client in the DB, by order of creation
+    pub insertion_order: usize,
 
+    // Only currencies the client had transactions in
+    balances: BalancesMap,
     deposits: TransactionsMap,
-    disputed_deposit_ids: UniqueTransactionIDs,
+    withdrawals: TransactionsMap,
+    disputes: DisputesMap,
     seen_transaction_ids: UniqueTransactionIDs,
+    // Freezes, unlocks and locking chargebacks, in the order they were applied
+    history: Vec<AdminAction>,
+    // Fees the client paid to the house
+    fees: FeesMap,
+    // What the entries changing its balances add up to (transfers are in the journals
+    //    of both clients). Storages that keep the entries themselves get them from `posted`
+    journal_totals: TrialBalance,
+    // Entries posted by the last transaction applied to it
+    posted: Vec<JournalEntry>,
 }
 
 impl Client {
     pub fn new(id: ClientID) -> Client {
         Client {
             id,
-            available: Decimal::from(0),
-            held: Decimal::from(0),
             locked: false,
+            insertion_order: 0,
+            balances: BalancesMap::new(),
             deposits: TransactionsMap::new(),
-            disputed_deposit_ids: UniqueTransactionIDs::new(),
+            withdrawals: TransactionsMap::new(),
+            disputes: DisputesMap::new(),
             seen_transaction_ids: UniqueTransactionIDs::new(),
+            history: vec![],
+            fees: FeesMap::new(),
+            journal_totals: TrialBalance::new(),
+            posted: vec![],
         }
     }
 
-    pub fn add_transaction(&mut self, txid: TransactionID, tx: Transaction) {
-        if !self.locked {
-            match self.process_transaction(txid, tx) {
-                Ok(_) => (),
-                Err(_e) => {
-                    // debug!("Transaction [{}]: {} ", txid, _e)
-                }
+    /// Funds of the client in `currency` (zero if it never had any).
+    pub fn balance(&self, currency: &str) -> Balance {
+        self.balances.get(currency).copied().unwrap_or_default()
+    }
+
+    /// Funds of the client in every currency it had transactions in, by currency.
+    pub fn balances(&self) -> &BalancesMap {
+        &self.balances
+    }
+
+    /// Currency the stored deposit/withdrawal `txid` moved funds in.
+    pub fn tx_currency(&self, txid: TransactionID) -> Option<&str> {
+        self.get_disputable_tx(txid).ok().map(Transaction::currency)
+    }
+
+    /// Fees the client paid, by currency.
+    pub fn fees(&self) -> &FeesMap {
+        &self.fees
+    }
+
+    /// What the journal entries posted by the client's transactions add up to.
+    ///
+    /// Its balances only ever change through them: transfers are in the journals of both
+    /// the sender and the recipient.
+    pub fn journal_totals(&self) -> &TrialBalance {
+        &self.journal_totals
+    }
+
+    /// Journal entries posted by the last transaction applied to the client (none if it
+    /// was rejected).
+    pub fn posted(&self) -> &[JournalEntry] {
+        &self.posted
+    }
+
+    /// Freezes and unlocks applied to the client, and chargebacks that locked it, oldest first.
+    pub fn history(&self) -> &[AdminAction] {
+        &self.history
+    }
+
+    /// Applies `tx` with the default `Policy`.
+    pub fn add_transaction(&mut self, txid: TransactionID, tx: Transaction) -> Result<(), Error> {
+        self.add_transaction_with(txid, tx, &Policy::default())
+    }
+
+    /// Applies `tx`, unless the client is locked and `policy` doesn't allow it.
+    ///
+    /// Resolves and chargebacks of open disputes are always allowed, so what a partial
+    /// chargeback left disputed (once it locked the client) can still be settled.
+    pub fn add_transaction_with(
+        &mut self,
+        txid: TransactionID,
+        tx: Transaction,
+        policy: &Policy,
+    ) -> Result<(), Error> {
+        self.posted.clear();
+        if self.locked && !policy.lock.allows(&tx.tx_type) && !self.settles(txid, &tx.tx_type) {
+            Err(Error::AccountLocked)
+        } else {
+            self.process_transaction(txid, tx, policy)
+        }
+    }
+
+    /// Moves the amount of the transfer `tx` from this client to `to`, in the currency
+    /// of `tx`. Either both clients change, or neither does.
+    pub fn transfer(
+        &mut self,
+        to: &mut Client,
+        txid: TransactionID,
+        tx: Transaction,
+        policy: &Policy,
+    ) -> Result<(), Error> {
+        // A missing amount is as invalid as a zero one
+        let amount = tx.amount.unwrap_or_default();
+        let currency = tx.currency();
+        self.posted.clear();
+        to.posted.clear();
+
+        if self.locked && !policy.lock.allows(&tx.tx_type) {
+            Err(Error::AccountLocked)
+        } else if to.locked && !policy.lock.allows(&tx.tx_type) {
+            Err(Error::RecipientLocked)
+        } else if self.id == to.id {
+            Err(Error::InvalidTransfer)
+        } else if self.seen_transaction_ids.contains(&txid) {
+            Err(Error::DuplicateTransaction)
+        } else if amount <= Decimal::ZERO {
+            Err(Error::InvalidAmount)
+        } else if self.balance(currency).available < amount {
+            Err(Error::NoAvailableFunds)
+        } else {
+            self.seen_transaction_ids.insert(txid);
+            let entry = JournalEntry {
+                tx: Some(txid),
+                currency: currency.to_string(),
+                debit: Account::Available(to.id),
+                credit: Account::Available(self.id),
+                amount,
             };
+            to.apply_entry(&entry);
+            self.apply_entry(&entry);
+            to.record(entry.clone());
+            self.record(entry);
+
+            Ok(())
+        }
+    }
+
+    // Used by storages that keep a copy of the transactions and disputes (eg. sqlite)
+    pub(crate) fn stored_transaction(&self, txid: TransactionID) -> Option<&Transaction> {
+        self.get_disputable_tx(txid).ok()
+    }
+
+    pub(crate) fn dispute_state(&self, txid: TransactionID) -> Option<&DisputeState> {
+        self.disputes.get(&txid)
+    }
+
+    pub(crate) fn has_seen(&self, txid: TransactionID) -> bool {
+        self.seen_transaction_ids.contains(&txid)
+    }
+
+    pub(crate) fn restore_transaction(&mut self, txid: TransactionID, tx: Transaction) {
+        match tx.tx_type {
+            TransactionType::Withdrawal => self.withdrawals.insert(txid, tx),
+            _ => self.deposits.insert(txid, tx),
+        };
+    }
+
+    pub(crate) fn restore_balance(&mut self, currency: Currency, balance: Balance) {
+        self.balances.insert(currency, balance);
+    }
+
+    pub(crate) fn restore_dispute(&mut self, txid: TransactionID, state: DisputeState) {
+        self.disputes.insert(txid, state);
+    }
+
+    pub(crate) fn restore_seen(&mut self, txid: TransactionID) {
+        self.seen_transaction_ids.insert(txid);
+    }
+
+    pub(crate) fn admin_action(&self, txid: TransactionID) -> Option<&AdminAction> {
+        self.history.iter().find(|action| action.tx == txid)
+    }
+
+    pub(crate) fn restore_admin_action(&mut self, action: AdminAction) {
+        self.history.push(action);
+    }
+
+    pub(crate) fn restore_fees(&mut self, currency: Currency, fees: Decimal) {
+        self.fees.insert(currency, fees);
+    }
+
+    pub(crate) fn restore_entry(&mut self, entry: &JournalEntry) {
+        self.journal_totals.add(entry);
+    }
+
+    /// What the transaction `txid` may change in the client, so it can be undone without
+    /// copying the whole client.
+    pub(crate) fn checkpoint(&self, txid: TransactionID) -> Checkpoint {
+        Checkpoint {
+            txid,
+            locked: self.locked,
+            balances: self.balances.clone(),
+            fees: self.fees.clone(),
+            deposit: self.deposits.contains_key(&txid),
+            withdrawal: self.withdrawals.contains_key(&txid),
+            dispute: self.disputes.get(&txid).cloned(),
+            seen: self.seen_transaction_ids.contains(&txid),
+            history: self.history.len(),
+        }
+    }
+
+    /// Undoes the transaction applied since `checkpoint` was taken, along with the
+    /// entries it posted.
+    pub(crate) fn undo(&mut self, checkpoint: Checkpoint) {
+        let txid = checkpoint.txid;
+        for entry in std::mem::take(&mut self.posted) {
+            self.journal_totals.remove(&entry);
+        }
+
+        self.locked = checkpoint.locked;
+        self.balances = checkpoint.balances;
+        self.fees = checkpoint.fees;
+        if !checkpoint.deposit {
+            self.deposits.remove(&txid);
+        }
+        if !checkpoint.withdrawal {
+            self.withdrawals.remove(&txid);
+        }
+        match checkpoint.dispute {
+            Some(state) => self.disputes.insert(txid, state),
+            None => self.disputes.remove(&txid),
+        };
+        if !checkpoint.seen {
+            self.seen_transaction_ids.remove(&txid);
+        }
+        self.history.truncate(checkpoint.history);
+    }
+
+    /// Opens a journal for balances that don't have one (eg. saved before journals
+    /// existed), from `Account::Opening`. Returns the entries it posted.
+    pub(crate) fn open_journal(&mut self) -> &[JournalEntry] {
+        self.posted.clear();
+        if !self.journal_totals.is_empty() {
+            return &[];
+        }
+
+        let balances = std::mem::take(&mut self.balances);
+        for (currency, balance) in &balances {
+            for (account, amount) in [
+                (Account::Available(self.id), balance.available),
+                (Account::Held(self.id), balance.held),
+            ] {
+                self.post(None, currency, account, Account::Opening, amount);
+            }
+        }
+
+        // Zero balances have no entries, but are still kept
+        for currency in balances.keys() {
+            self.balances.entry(currency.clone()).or_default();
+        }
+
+        &self.posted
+    }
+
+    fn balance_mut(&mut self, currency: &str) -> &mut Balance {
+        self.balances.entry(currency.to_string()).or_default()
+    }
+
+    // Records a balanced entry moving `amount` from `credit` to `debit`,
+    //    and applies it to the balances
+    fn post(
+        &mut self,
+        txid: Option<TransactionID>,
+        currency: &str,
+        debit: Account,
+        credit: Account,
+        amount: Decimal,
+    ) {
+        if amount.is_zero() {
+            return;
+        }
+
+        let entry = JournalEntry {
+            tx: txid,
+            currency: currency.to_string(),
+            debit,
+            credit,
+            amount,
+        };
+        self.apply_entry(&entry);
+        self.record(entry);
+    }
+
+    fn record(&mut self, entry: JournalEntry) {
+        self.journal_totals.add(&entry);
+        self.posted.push(entry);
+    }
+
+    // Only the accounts of this client are kept in its balances
+    fn apply_entry(&mut self, entry: &JournalEntry) {
+        for (account, amount) in [(entry.debit, entry.amount), (entry.credit, -entry.amount)] {
+            match account {
+                Account::Available(id) if id == self.id => {
+                    self.balance_mut(&entry.currency).available += amount
+                }
+                Account::Held(id) if id == self.id => {
+                    self.balance_mut(&entry.currency).held += amount
+                }
+                _ => (),
+            }
+        }
+    }
+
+    // Moves `fee` from the available funds to the house
+    fn charge_fee(&mut self, txid: TransactionID, currency: &str, fee: Decimal) {
+        if !fee.is_zero() {
+            let available = Account::Available(self.id);
+            self.post(Some(txid), currency, Account::Revenue, available, fee);
+            *self.fees.entry(currency.to_string()).or_default() += fee;
         }
     }
 
-    fn get_tx_amount(&self, txid: TransactionID) -> Result<Decimal, Error> {
+    // Whether `tx_type` settles (part of) the open dispute of `txid`
+    fn settles(&self, txid: TransactionID, tx_type: &TransactionType) -> bool {
+        matches!(
+            tx_type,
+            TransactionType::Resolve | TransactionType::Chargeback
+        ) && self
+            .disputes
+            .get(&txid)
+            .is_some_and(|state| !state.disputed.is_zero())
+    }
+
+    // Only deposits and withdrawals can be disputed
+    fn get_disputable_tx(&self, txid: TransactionID) -> Result<&Transaction, Error> {
         self.deposits
             .get(&txid)
-            .ok_or(Error::TransactionAccessError)?
-            .get_amount()
+            .or_else(|| self.withdrawals.get(&txid))
+            .ok_or(Error::TransactionNotFound)
     }
 
-    fn process_transaction(&mut self, txid: TransactionID, tx: Transaction) -> Result<(), Error> {
+    fn process_transaction(
+        &mut self,
+        txid: TransactionID,
+        tx: Transaction,
+        policy: &Policy,
+    ) -> Result<(), Error> {
         match tx.tx_type {
             TransactionType::Deposit => self.deposit(txid, tx),
-            TransactionType::Withdrawal => self.withdrawal(txid, tx),
-            TransactionType::Dispute => self.dispute(txid),
-            TransactionType::Resolve => self.resolve(txid),
-            TransactionType::Chargeback => self.chargeback(txid),
+            TransactionType::Withdrawal => self.withdrawal(txid, tx, policy),
+            TransactionType::Dispute => self.dispute(txid, tx.amount, policy.disputes),
+            TransactionType::Resolve => self.resolve(txid, tx.amount),
+            TransactionType::Chargeback => self.chargeback(txid, tx.amount, policy),
+            TransactionType::Freeze | TransactionType::Unlock => self.admin(txid, tx),
+            TransactionType::Convert => self.convert(txid, tx, &policy.conversion),
+            // Needs the other client as well, see `transfer`
+            TransactionType::Transfer => Err(Error::InvalidTransfer),
+            TransactionType::Fee => self.fee(txid, tx),
         }
     }
     fn deposit(&mut self, txid: TransactionID, tx: Transaction) -> Result<(), Error> {
@@ -69,24 +392,46 @@ impl Client {
 
             let amount = tx.get_amount()?;
 
-            self.available += amount;
+            let available = Account::Available(self.id);
+            self.post(
+                Some(txid),
+                tx.currency(),
+                available,
+                Account::Funding,
+                amount,
+            );
             self.deposits.insert(txid, tx);
             Ok(())
         }
     }
 
-    fn withdrawal(&mut self, txid: TransactionID, tx: Transaction) -> Result<(), Error> {
+    // The withdrawal fee (if any) must be available along with the amount
+    fn withdrawal(
+        &mut self,
+        txid: TransactionID,
+        tx: Transaction,
+        policy: &Policy,
+    ) -> Result<(), Error> {
         if self.seen_transaction_ids.contains(&txid) {
             Err(Error::DuplicateTransaction)
         } else if tx.amount.is_none() {
             Err(Error::InvalidAmount)
         } else {
-            self.seen_transaction_ids.insert(txid);
-
             let amount = tx.get_amount()?;
-
-            if self.available >= amount {
-                self.available -= amount;
+            let fee = policy.fees.fee(&tx.tx_type, amount);
+
+            if self.balance(tx.currency()).available >= amount + fee {
+                self.seen_transaction_ids.insert(txid);
+                let available = Account::Available(self.id);
+                self.post(
+                    Some(txid),
+                    tx.currency(),
+                    Account::Funding,
+                    available,
+                    amount,
+                );
+                self.charge_fee(txid, tx.currency(), fee);
+                self.withdrawals.insert(txid, tx);
                 Ok(())
             } else {
                 Err(Error::NoAvailableFunds)
@@ -94,101 +439,457 @@ impl Client {
         }
     }
 
-    fn dispute(&mut self, txid: TransactionID) -> Result<(), Error> {
-        if !self.deposits.contains_key(&txid) {
-            Err(Error::TransactionNotFound)
-        } else if self.disputed_deposit_ids.contains(&txid) {
-            Err(Error::DuplicateDispute)
+    fn fee(&mut self, txid: TransactionID, tx: Transaction) -> Result<(), Error> {
+        // A missing amount is as invalid as a zero one
+        let amount = tx.amount.unwrap_or_default();
+
+        if self.seen_transaction_ids.contains(&txid) {
+            Err(Error::DuplicateTransaction)
+        } else if amount <= Decimal::ZERO {
+            Err(Error::InvalidAmount)
+        } else if self.balance(tx.currency()).available < amount {
+            Err(Error::NoAvailableFunds)
         } else {
-            let disputed_amount = self.get_tx_amount(txid)?;
+            self.seen_transaction_ids.insert(txid);
+            self.charge_fee(txid, tx.currency(), amount);
+
+            Ok(())
+        }
+    }
 
-            let result = if self.available >= disputed_amount {
-                self.available -= disputed_amount;
-                self.held += disputed_amount;
-                self.disputed_deposit_ids.insert(txid);
+    // Converts the amount of `currency` into `to_currency`, at the rate effective when
+    //    the transaction happened (or the one it carries). Conversions can't be disputed.
+    fn convert(
+        &mut self,
+        txid: TransactionID,
+        tx: Transaction,
+        policy: &ConversionPolicy,
+    ) -> Result<(), Error> {
+        if self.seen_transaction_ids.contains(&txid) {
+            return Err(Error::DuplicateTransaction);
+        }
 
-                Ok(())
-            } else {
-                Err(Error::NoAvailableFunds)
-            };
+        let amount = tx.get_amount()?;
+        let from = tx.currency();
+        let to = match tx.to_currency.as_deref() {
+            Some(to) if to != from => to,
+            _ => return Err(Error::InvalidConversion),
+        };
+        let at = tx.timestamp.ok_or(Error::MissingTimestamp)?;
+        let rate = tx
+            .rate
+            .or_else(|| policy.rates.rate(from, to, Some(at)))
+            .ok_or(Error::UnknownRate)?;
+        let converted = policy.rounding.round(amount * rate);
+
+        if amount <= Decimal::ZERO || converted <= Decimal::ZERO {
+            Err(Error::InvalidAmount)
+        } else if self.balance(from).available < amount {
+            Err(Error::NoAvailableFunds)
+        } else {
+            self.seen_transaction_ids.insert(txid);
+            let available = Account::Available(self.id);
+            self.post(Some(txid), from, Account::Exchange, available, amount);
+            self.post(Some(txid), to, available, Account::Exchange, converted);
 
-            result
+            Ok(())
         }
     }
 
-    fn resolve(&mut self, txid: TransactionID) -> Result<(), Error> {
-        if !self.deposits.contains_key(&txid) {
-            Err(Error::TransactionNotFound)
-        } else if !self.disputed_deposit_ids.contains(&txid) {
-            Err(Error::DisputeNotFound)
+    // Disputing a deposit holds its funds, until it's resolved (released)
+    //    or charged back (removed).
+    // Disputing a withdrawal holds the withdrawn amount, until it's resolved (removed)
+    //    or charged back (credited back to available).
+    // Disputes may carry an amount, to only dispute part of the transaction.
+    // Funds are held in the currency of the disputed transaction.
+    // With `DisputePolicy::Full`, a deposit is held even if it was already withdrawn,
+    //    leaving `available` negative.
+    fn dispute(
+        &mut self,
+        txid: TransactionID,
+        amount: Option<Decimal>,
+        policy: DisputePolicy,
+    ) -> Result<(), Error> {
+        let disputed_tx = self.get_disputable_tx(txid)?;
+        let disputed_type = disputed_tx.tx_type.clone();
+        let currency = disputed_tx.currency().to_string();
+        let tx_amount = disputed_tx.get_amount()?;
+        let available = self.balance(&currency).available;
+
+        let state = self.disputes.get(&txid).cloned().unwrap_or_default();
+        let undisputed_amount = tx_amount - state.disputed - state.charged_back;
+        let disputed_amount = amount.unwrap_or(undisputed_amount);
+
+        if undisputed_amount <= Decimal::ZERO {
+            Err(Error::DuplicateDispute)
+        } else if disputed_amount <= Decimal::ZERO {
+            Err(Error::InvalidAmount)
+        } else if disputed_amount > undisputed_amount {
+            Err(Error::DisputeAmountExceeded)
+        } else if disputed_type == TransactionType::Withdrawal {
+            // Claimed back from where the withdrawal went
+            let held = Account::Held(self.id);
+            self.post(
+                Some(txid),
+                &currency,
+                held,
+                Account::Funding,
+                disputed_amount,
+            );
+            self.disputes.entry(txid).or_default().disputed += disputed_amount;
+
+            Ok(())
+        } else if available >= disputed_amount || policy == DisputePolicy::Full {
+            let (available, held) = (Account::Available(self.id), Account::Held(self.id));
+            self.post(Some(txid), &currency, held, available, disputed_amount);
+            self.disputes.entry(txid).or_default().disputed += disputed_amount;
+
+            Ok(())
         } else {
-            let disputed_amount = self.get_tx_amount(txid)?;
+            Err(Error::NoAvailableFunds)
+        }
+    }
+
+    // Resolves and chargebacks act on the whole disputed amount, unless they carry an amount
+    fn get_settled_amount(
+        &self,
+        txid: TransactionID,
+        amount: Option<Decimal>,
+    ) -> Result<(TransactionType, Currency, Decimal), Error> {
+        let disputed_tx = self.get_disputable_tx(txid)?;
+        let disputed_type = disputed_tx.tx_type.clone();
+        let currency = disputed_tx.currency().to_string();
+
+        let disputed = match self.disputes.get(&txid) {
+            Some(state) if !state.disputed.is_zero() => state.disputed,
+            _ => return Err(Error::DisputeNotFound),
+        };
+        let settled_amount = amount.unwrap_or(disputed);
 
-            let result = if self.held >= disputed_amount {
-                self.available += disputed_amount;
-                self.held -= disputed_amount;
-                self.disputed_deposit_ids.remove(&txid);
+        if settled_amount <= Decimal::ZERO {
+            Err(Error::InvalidAmount)
+        } else if settled_amount > disputed {
+            Err(Error::DisputeAmountExceeded)
+        } else {
+            Ok((disputed_type, currency, settled_amount))
+        }
+    }
 
-                Ok(())
+    fn settle_dispute(&mut self, txid: TransactionID, amount: Decimal, charged_back: bool) {
+        if let Some(state) = self.disputes.get_mut(&txid) {
+            state.disputed -= amount;
+            if charged_back {
+                state.charged_back += amount;
+            }
+            if state.is_empty() {
+                self.disputes.remove(&txid);
+            }
+        }
+    }
+
+    fn resolve(&mut self, txid: TransactionID, amount: Option<Decimal>) -> Result<(), Error> {
+        let (disputed_type, currency, resolved_amount) = self.get_settled_amount(txid, amount)?;
+
+        if self.balance(&currency).held >= resolved_amount {
+            // Released to the client, or given back to where the withdrawal went
+            let released_to = match disputed_type {
+                TransactionType::Deposit => Account::Available(self.id),
+                _ => Account::Funding,
+            };
+            let held = Account::Held(self.id);
+            self.post(Some(txid), &currency, released_to, held, resolved_amount);
+            self.settle_dispute(txid, resolved_amount, false);
+
+            Ok(())
+        } else {
+            Err(Error::MissingHeldFunds)
+        }
+    }
+
+    // The chargeback fee (if any) is always charged, even if it leaves `available` negative:
+    //    the client owes it
+    // A transaction charged back in parts is charged the fee on the whole charged back
+    //    amount, once: each part only adds what the fee grew by
+    fn chargeback(
+        &mut self,
+        txid: TransactionID,
+        amount: Option<Decimal>,
+        policy: &Policy,
+    ) -> Result<(), Error> {
+        let (disputed_type, currency, charged_back_amount) =
+            self.get_settled_amount(txid, amount)?;
+        let fee_on = |amount: Decimal| {
+            if amount.is_zero() {
+                Decimal::ZERO
             } else {
-                Err(Error::MissingHeldFunds)
+                policy.fees.fee(&TransactionType::Chargeback, amount)
+            }
+        };
+        let charged_back = self
+            .disputes
+            .get(&txid)
+            .map_or(Decimal::ZERO, |state| state.charged_back);
+        let fee = fee_on(charged_back + charged_back_amount) - fee_on(charged_back);
+
+        if self.balance(&currency).held >= charged_back_amount {
+            // Lost for a deposit, credited back to the client for a withdrawal
+            let charged_back_to = match disputed_type {
+                TransactionType::Withdrawal => Account::Available(self.id),
+                _ => Account::ChargebackLoss,
             };
+            let held = Account::Held(self.id);
+            self.post(
+                Some(txid),
+                &currency,
+                charged_back_to,
+                held,
+                charged_back_amount,
+            );
+            self.settle_dispute(txid, charged_back_amount, true);
+            self.charge_fee(txid, &currency, fee);
+            if !self.locked {
+                self.locked = true;
+                self.history.push(AdminAction {
+                    tx: txid,
+                    tx_type: TransactionType::Chargeback,
+                    reason: String::new(),
+                });
+            }
 
-            result
+            Ok(())
+        } else {
+            Err(Error::NotEnoughChargeback)
         }
     }
 
-    fn chargeback(&mut self, txid: TransactionID) -> Result<(), Error> {
-        if !self.deposits.contains_key(&txid) {
-            Err(Error::TransactionNotFound)
-        } else if !self.disputed_deposit_ids.contains(&txid) {
-            Err(Error::DisputeNotFound)
+    // Freezing a locked client only gets here if the lock policy allows it,
+    //    in which case it's just recorded
+    fn admin(&mut self, txid: TransactionID, tx: Transaction) -> Result<(), Error> {
+        let reason = match tx.reason {
+            Some(reason) if !reason.trim().is_empty() => reason,
+            _ => return Err(Error::MissingReason),
+        };
+
+        if self.seen_transaction_ids.contains(&txid) {
+            Err(Error::DuplicateTransaction)
+        } else if tx.tx_type == TransactionType::Unlock && !self.locked {
+            Err(Error::AccountNotLocked)
         } else {
-            let disputed_amount = self.get_tx_amount(txid)?;
+            self.seen_transaction_ids.insert(txid);
+            self.locked = tx.tx_type == TransactionType::Freeze;
+            self.history.push(AdminAction {
+                tx: txid,
+                tx_type: tx.tx_type,
+                reason,
+            });
 
-            let result = if self.held >= disputed_amount {
-                self.held -= disputed_amount;
-                self.disputed_deposit_ids.remove(&txid);
-                self.locked = true;
+            Ok(())
+        }
+    }
+}
 
-                Ok(())
-            } else {
-                Err(Error::NotEnoughChargeback)
-            };
+/// What a transaction may change in a client (see `Client::checkpoint`): its lock, its
+/// balances and fees (a client only has a few currencies), and what's kept under the id
+/// of the transaction.
+pub(crate) struct Checkpoint {
+    txid: TransactionID,
+    locked: bool,
+    balances: BalancesMap,
+    fees: FeesMap,
+    deposit: bool,
+    withdrawal: bool,
+    dispute: Option<DisputeState>,
+    seen: bool,
+    history: usize,
+}
+
+/// Complete state of a client, including its transactions and disputes, used for snapshots.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientState {
+    id: ClientID,
+    locked: bool,
+    insertion_order: usize,
+
+    balances: BalancesMap,
+    deposits: TransactionsMap,
+    withdrawals: TransactionsMap,
+    disputes: DisputesMap,
+    seen_transaction_ids: UniqueTransactionIDs,
+    // Missing from snapshots taken before freezes/unlocks existed
+    #[serde(default)]
+    history: Vec<AdminAction>,
+    // Missing from snapshots taken before fees existed
+    #[serde(default)]
+    fees: FeesMap,
+    // What the journal adds up to. Missing from snapshots taken before journals existed,
+    //    which get an opening one
+    #[serde(default)]
+    journal_totals: Option<Vec<(Currency, Account, Totals)>>,
+    // The whole journal, only in snapshots of version 3
+    #[serde(default, skip_serializing)]
+    journal: Option<Vec<JournalEntry>>,
+}
+
+impl From<&Client> for ClientState {
+    fn from(client: &Client) -> Self {
+        ClientState {
+            id: client.id,
+            locked: client.locked,
+            insertion_order: client.insertion_order,
+            balances: client.balances.clone(),
+            deposits: client.deposits.clone(),
+            withdrawals: client.withdrawals.clone(),
+            disputes: client.disputes.clone(),
+            seen_transaction_ids: client.seen_transaction_ids.clone(),
+            history: client.history.clone(),
+            fees: client.fees.clone(),
+            journal_totals: Some(
+                client
+                    .journal_totals
+                    .accounts()
+                    .map(|(currency, account, totals)| (currency.to_string(), account, totals))
+                    .collect(),
+            ),
+            journal: None,
+        }
+    }
+}
+
+impl From<ClientState> for Client {
+    fn from(state: ClientState) -> Self {
+        let opened = state.journal_totals.is_some() || state.journal.is_some();
+        let mut journal_totals = TrialBalance::new();
+        for (currency, account, totals) in state.journal_totals.iter().flatten() {
+            journal_totals.add_totals(currency, *account, *totals);
+        }
+        for entry in state.journal.iter().flatten() {
+            journal_totals.add(entry);
+        }
 
-            result
+        let mut client = Client {
+            id: state.id,
+            locked: state.locked,
+            insertion_order: state.insertion_order,
+            balances: state.balances,
+            deposits: state.deposits,
+            withdrawals: state.withdrawals,
+            disputes: state.disputes,
+            seen_transaction_ids: state.seen_transaction_ids,
+            history: state.history,
+            fees: state.fees,
+            journal_totals,
+            posted: vec![],
+        };
+        if !opened {
+            client.open_journal();
+        }
+
+        client
+    }
+}
+
+/// Adds the transfers each of `clients` received to its journal, for journals that only
+/// had them in the journal of the sender (snapshots before version 5).
+pub(crate) fn add_received_transfers(clients: &mut [Client]) {
+    let mut received = vec![];
+    for sender in clients.iter() {
+        for (currency, account, totals) in sender.journal_totals.accounts() {
+            match account {
+                Account::Available(recipient) if recipient != sender.id => {
+                    received.push((recipient, sender.id, currency.to_string(), totals))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let positions: HashMap<ClientID, usize> = clients
+        .iter()
+        .enumerate()
+        .map(|(i, client)| (client.id, i))
+        .collect();
+    for (recipient, sender, currency, totals) in received {
+        if let Some(&i) = positions.get(&recipient) {
+            let journal = &mut clients[i].journal_totals;
+            journal.add_totals(&currency, Account::Available(recipient), totals);
+            journal.add_totals(
+                &currency,
+                Account::Available(sender),
+                Totals {
+                    debits: totals.credits,
+                    credits: totals.debits,
+                },
+            );
         }
     }
 }
 
-#[derive(Serialize)]
+/// Funds of a client in one currency, as they are output.
+#[derive(Serialize, Debug, Clone, PartialEq)]
 pub struct SerializableClient {
-    client: ClientID,
-    available: Decimal,
-    held: Decimal,
-    total: Decimal,
-    locked: bool,
+    pub client: ClientID,
+    pub currency: Currency,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
 }
 
-impl Serialize for Client {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
+impl SerializableClient {
+    pub fn new(client: &Client, currency: &str) -> SerializableClient {
+        let balance = client.balance(currency);
         SerializableClient {
-            client: self.id,
-            available: self.available.round_dp(4),
-            held: self.held.round_dp(4),
-            total: (self.held + self.available).round_dp(4),
-            locked: self.locked,
+            client: client.id,
+            currency: currency.to_string(),
+            available: balance.available.round_dp(4),
+            held: balance.held.round_dp(4),
+            total: (balance.held + balance.available).round_dp(4),
+            locked: client.locked,
+        }
+    }
+
+    /// State of the client in the currency transaction `tx_id` moved funds in: `currency`,
+    /// or the one of the transaction it disputes/resolves/charges back.
+    pub fn after(
+        client: &Client,
+        tx_id: TransactionID,
+        tx_type: &TransactionType,
+        currency: Option<&str>,
+    ) -> SerializableClient {
+        let disputed_currency = match tx_type {
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                client.tx_currency(tx_id)
+            }
+            _ => None,
+        };
+        let currency = disputed_currency.or(currency).unwrap_or(NO_CURRENCY);
+
+        SerializableClient::new(client, currency)
+    }
+
+    /// One row per currency of the client, by currency.
+    /// A client without any funds (eg. all its transactions were rejected) still gets one.
+    pub fn rows(client: &Client) -> Vec<SerializableClient> {
+        if client.balances.is_empty() {
+            return vec![SerializableClient::new(client, NO_CURRENCY)];
         }
-        .serialize(serializer)
+
+        client
+            .balances
+            .keys()
+            .map(|currency| SerializableClient::new(client, currency))
+            .collect()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        client::policy::LockPolicy,
+        fees::{Fee, FeeSchedule},
+        rates::RateTable,
+    };
     use std;
     use std::{panic, str::FromStr};
 
@@ -209,57 +910,65 @@ mod tests {
         let mut client = Client::new(1);
 
         for txid in 0..INIT_DEPOSIT_COUNT {
-            client.add_transaction(
-                txid,
-                Transaction {
-                    tx_type: TransactionType::Deposit,
-                    amount: Some(Decimal::from(DEPOSIT_AMOUNT)),
-                },
-            );
+            client
+                .add_transaction(
+                    txid,
+                    Transaction::new(
+                        TransactionType::Deposit,
+                        Some(Decimal::from(DEPOSIT_AMOUNT)),
+                    ),
+                )
+                .unwrap();
         }
 
         assert_eq!(
             Decimal::from(DEPOSIT_AMOUNT * INIT_DEPOSIT_COUNT),
-            client.available
+            client.balance(NO_CURRENCY).available
         );
-        assert_eq!(Decimal::from(0), client.held);
-        assert_eq!(false, client.locked);
+        assert_eq!(Decimal::from(0), client.balance(NO_CURRENCY).held);
+        assert!(!client.locked);
 
         client
     }
 
     fn test_ignored(client: &mut Client, txid: TransactionID, tx: Transaction) {
-        let prev_available = client.available;
-        let prev_held = client.held;
+        let prev_available = client.balance(NO_CURRENCY).available;
+        let prev_held = client.balance(NO_CURRENCY).held;
 
-        client.add_transaction(txid, tx);
+        assert!(client.add_transaction(txid, tx).is_err());
 
-        assert_eq!(prev_available, client.available);
-        assert_eq!(prev_held, client.held);
+        assert_eq!(prev_available, client.balance(NO_CURRENCY).available);
+        assert_eq!(prev_held, client.balance(NO_CURRENCY).held);
     }
 
     fn test_success_dispute(client: &mut Client, txid: TransactionID, tx: Transaction) {
-        let prev_available = client.available;
-        let prev_held = client.held;
+        let prev_available = client.balance(NO_CURRENCY).available;
+        let prev_held = client.balance(NO_CURRENCY).held;
 
-        client.add_transaction(txid, tx);
+        client.add_transaction(txid, tx).unwrap();
 
-        assert_eq!(client.held, prev_held + Decimal::from(DEPOSIT_AMOUNT));
         assert_eq!(
-            client.available,
+            client.balance(NO_CURRENCY).held,
+            prev_held + Decimal::from(DEPOSIT_AMOUNT)
+        );
+        assert_eq!(
+            client.balance(NO_CURRENCY).available,
             prev_available - Decimal::from(DEPOSIT_AMOUNT)
         );
     }
 
     fn test_success_resolve(client: &mut Client, txid: TransactionID, tx: Transaction) {
-        let prev_available = client.available;
-        let prev_held = client.held;
+        let prev_available = client.balance(NO_CURRENCY).available;
+        let prev_held = client.balance(NO_CURRENCY).held;
 
-        client.add_transaction(txid, tx);
+        client.add_transaction(txid, tx).unwrap();
 
-        assert_eq!(client.held, prev_held - Decimal::from(DEPOSIT_AMOUNT));
         assert_eq!(
-            client.available,
+            client.balance(NO_CURRENCY).held,
+            prev_held - Decimal::from(DEPOSIT_AMOUNT)
+        );
+        assert_eq!(
+            client.balance(NO_CURRENCY).available,
             prev_available + Decimal::from(DEPOSIT_AMOUNT)
         );
     }
@@ -268,51 +977,54 @@ mod tests {
     async fn test_duplicate_id() {
         let mut client = init();
 
-        let initial_amount = client.available;
+        let initial_amount = client.balance(NO_CURRENCY).available;
 
-        client.add_transaction(
-            1,
-            Transaction {
-                tx_type: TransactionType::Deposit,
-                amount: Some(Decimal::from(DEPOSIT_AMOUNT)),
-            },
+        assert_eq!(
+            client.add_transaction(
+                1,
+                Transaction::new(
+                    TransactionType::Deposit,
+                    Some(Decimal::from(DEPOSIT_AMOUNT))
+                )
+            ),
+            Err(Error::DuplicateTransaction)
         );
 
-        assert_eq!(initial_amount, client.available);
+        assert_eq!(initial_amount, client.balance(NO_CURRENCY).available);
 
-        client.add_transaction(
-            1,
-            Transaction {
-                tx_type: TransactionType::Withdrawal,
-                amount: Some(Decimal::from(DEPOSIT_AMOUNT)),
-            },
+        assert_eq!(
+            client.add_transaction(
+                1,
+                Transaction::new(
+                    TransactionType::Withdrawal,
+                    Some(Decimal::from(DEPOSIT_AMOUNT))
+                )
+            ),
+            Err(Error::DuplicateTransaction)
         );
-        assert_eq!(initial_amount, client.available);
+        assert_eq!(initial_amount, client.balance(NO_CURRENCY).available);
     }
 
     #[tokio::test]
     async fn test_disputes_and_resolves() {
         let mut client = init();
 
-        assert!(client.available > Decimal::from(0));
-        assert_eq!(client.held, Decimal::from(0));
+        assert!(client.balance(NO_CURRENCY).available > Decimal::from(0));
+        assert_eq!(client.balance(NO_CURRENCY).held, Decimal::from(0));
 
-        let dispute_tx = Transaction {
-            tx_type: TransactionType::Dispute,
-            amount: None,
-        };
+        let dispute_tx = Transaction::new(TransactionType::Dispute, None);
 
-        let resolve_tx = Transaction {
-            tx_type: TransactionType::Resolve,
-            amount: None,
-        };
+        let resolve_tx = Transaction::new(TransactionType::Resolve, None);
 
         let valid_dispute_id = 1;
 
-        // Test dispute tx ID & passing the amount field, which should be ignored
+        // Test disputing more than the deposited amount
         let mut dispute_with_amount = dispute_tx.clone();
         dispute_with_amount.amount = Some(Decimal::from(DEPOSIT_AMOUNT + 1000));
-        test_success_dispute(&mut client, valid_dispute_id, dispute_with_amount);
+        test_ignored(&mut client, valid_dispute_id, dispute_with_amount);
+
+        // Test dispute tx ID
+        test_success_dispute(&mut client, valid_dispute_id, dispute_tx.clone());
 
         // Test duplicate dispute
         test_ignored(&mut client, valid_dispute_id, dispute_tx.clone());
@@ -324,19 +1036,16 @@ mod tests {
         test_ignored(&mut client, 101, resolve_tx.clone());
 
         // Test withdrawling all funds when on dispute
-        let total = client.available + client.held;
+        let total = client.balance(NO_CURRENCY).available + client.balance(NO_CURRENCY).held;
         test_ignored(
             &mut client,
             INIT_DEPOSIT_COUNT + 1,
-            Transaction {
-                tx_type: TransactionType::Withdrawal,
-                amount: Some(total),
-            },
+            Transaction::new(TransactionType::Withdrawal, Some(total)),
         );
 
         // Test resolving
         test_success_resolve(&mut client, valid_dispute_id, resolve_tx.clone());
-        assert_eq!(client.held, Decimal::from(0));
+        assert_eq!(client.balance(NO_CURRENCY).held, Decimal::from(0));
 
         // Test resolving again
         test_ignored(&mut client, valid_dispute_id, resolve_tx.clone());
@@ -346,34 +1055,30 @@ mod tests {
 
         // Test resolving
         test_success_resolve(&mut client, valid_dispute_id, resolve_tx.clone());
-        assert_eq!(client.held, Decimal::from(0));
+        assert_eq!(client.balance(NO_CURRENCY).held, Decimal::from(0));
 
         // Test withdrawling all funds after resolving
-        let prev_available = client.available;
-        let prev_held = client.held;
+        let prev_available = client.balance(NO_CURRENCY).available;
+        let prev_held = client.balance(NO_CURRENCY).held;
 
-        client.add_transaction(
-            INIT_DEPOSIT_COUNT + 2,
-            Transaction {
-                tx_type: TransactionType::Withdrawal,
-                amount: Some(prev_available + prev_held),
-            },
-        );
-        assert_eq!(client.available, Decimal::from(0));
-        assert_eq!(client.held, Decimal::from(0));
+        client
+            .add_transaction(
+                INIT_DEPOSIT_COUNT + 2,
+                Transaction::new(
+                    TransactionType::Withdrawal,
+                    Some(prev_available + prev_held),
+                ),
+            )
+            .unwrap();
+        assert_eq!(client.balance(NO_CURRENCY).available, Decimal::from(0));
+        assert_eq!(client.balance(NO_CURRENCY).held, Decimal::from(0));
     }
 
     #[tokio::test]
     async fn test_chargeback() {
         let mut client = init();
-        let dispute_tx = Transaction {
-            tx_type: TransactionType::Dispute,
-            amount: None,
-        };
-        let chargeback_tx = Transaction {
-            tx_type: TransactionType::Chargeback,
-            amount: None,
-        };
+        let dispute_tx = Transaction::new(TransactionType::Dispute, None);
+        let chargeback_tx = Transaction::new(TransactionType::Chargeback, None);
         let dispute_id = 1;
 
         test_success_dispute(&mut client, dispute_id, dispute_tx);
@@ -382,13 +1087,18 @@ mod tests {
         test_ignored(&mut client, 100, chargeback_tx.clone());
 
         // Test chargeback
-        let prev_available = client.available;
-        let prev_held = client.held;
+        let prev_available = client.balance(NO_CURRENCY).available;
+        let prev_held = client.balance(NO_CURRENCY).held;
 
-        client.add_transaction(dispute_id, chargeback_tx.clone());
+        client
+            .add_transaction(dispute_id, chargeback_tx.clone())
+            .unwrap();
 
-        assert_eq!(client.held, prev_held - Decimal::from(DEPOSIT_AMOUNT));
-        assert_eq!(client.available, prev_available);
+        assert_eq!(
+            client.balance(NO_CURRENCY).held,
+            prev_held - Decimal::from(DEPOSIT_AMOUNT)
+        );
+        assert_eq!(client.balance(NO_CURRENCY).available, prev_available);
 
         // Test chargeback same id [ it's locked, so it should be ignored]
         test_ignored(&mut client, dispute_id, chargeback_tx.clone());
@@ -397,59 +1107,820 @@ mod tests {
         test_ignored(
             &mut client,
             100,
-            Transaction {
-                tx_type: TransactionType::Deposit,
-                amount: Some(Decimal::from(DEPOSIT_AMOUNT)),
-            },
+            Transaction::new(
+                TransactionType::Deposit,
+                Some(Decimal::from(DEPOSIT_AMOUNT)),
+            ),
         );
 
         test_ignored(
             &mut client,
             101,
-            Transaction {
-                tx_type: TransactionType::Withdrawal,
-                amount: Some(Decimal::from(DEPOSIT_AMOUNT)),
-            },
+            Transaction::new(
+                TransactionType::Withdrawal,
+                Some(Decimal::from(DEPOSIT_AMOUNT)),
+            ),
         );
 
         test_ignored(
             &mut client,
             1,
-            Transaction {
-                tx_type: TransactionType::Dispute,
-                amount: Some(Decimal::from(DEPOSIT_AMOUNT)),
-            },
+            Transaction::new(
+                TransactionType::Dispute,
+                Some(Decimal::from(DEPOSIT_AMOUNT)),
+            ),
         );
 
         test_ignored(
             &mut client,
             1,
-            Transaction {
-                tx_type: TransactionType::Resolve,
-                amount: Some(Decimal::from(DEPOSIT_AMOUNT)),
-            },
+            Transaction::new(
+                TransactionType::Resolve,
+                Some(Decimal::from(DEPOSIT_AMOUNT)),
+            ),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_partial_disputes() {
+        let mut client = init();
+        let txid = 1;
+        let amount_tx = |tx_type: TransactionType, amount: Option<u32>| {
+            Transaction::new(tx_type, amount.map(Decimal::from))
+        };
+
+        client
+            .add_transaction(txid, amount_tx(TransactionType::Dispute, Some(40)))
+            .unwrap();
+        client
+            .add_transaction(txid, amount_tx(TransactionType::Dispute, Some(30)))
+            .unwrap();
+        assert_eq!(client.balance(NO_CURRENCY).held, Decimal::from(70));
+
+        // Test disputing more than the remaining undisputed amount
+        assert_eq!(
+            client.add_transaction(txid, amount_tx(TransactionType::Dispute, Some(40))),
+            Err(Error::DisputeAmountExceeded)
+        );
+
+        // Test disputing without an amount, which disputes the remaining amount
+        client
+            .add_transaction(txid, amount_tx(TransactionType::Dispute, None))
+            .unwrap();
+        assert_eq!(
+            client.balance(NO_CURRENCY).held,
+            Decimal::from(DEPOSIT_AMOUNT)
+        );
+        assert_eq!(
+            client.add_transaction(txid, amount_tx(TransactionType::Dispute, None)),
+            Err(Error::DuplicateDispute)
+        );
+
+        // Test partially resolving
+        client
+            .add_transaction(txid, amount_tx(TransactionType::Resolve, Some(50)))
+            .unwrap();
+        assert_eq!(client.balance(NO_CURRENCY).held, Decimal::from(50));
+        assert_eq!(
+            client.balance(NO_CURRENCY).available,
+            Decimal::from(DEPOSIT_AMOUNT * INIT_DEPOSIT_COUNT - 50)
+        );
+
+        // Test resolving more than the disputed amount
+        assert_eq!(
+            client.add_transaction(txid, amount_tx(TransactionType::Resolve, Some(51))),
+            Err(Error::DisputeAmountExceeded)
+        );
+
+        // Test partially charging back
+        client
+            .add_transaction(txid, amount_tx(TransactionType::Chargeback, Some(20)))
+            .unwrap();
+        assert_eq!(client.balance(NO_CURRENCY).held, Decimal::from(30));
+        assert_eq!(
+            client.balance(NO_CURRENCY).available,
+            Decimal::from(DEPOSIT_AMOUNT * INIT_DEPOSIT_COUNT - 50)
+        );
+        assert!(client.locked);
+
+        // Test settling the rest of the dispute, which the lock doesn't stop
+        client
+            .add_transaction(txid, amount_tx(TransactionType::Resolve, Some(10)))
+            .unwrap();
+        client
+            .add_transaction(txid, amount_tx(TransactionType::Chargeback, None))
+            .unwrap();
+        assert_eq!(client.balance(NO_CURRENCY).held, Decimal::ZERO);
+        assert_eq!(
+            client.balance(NO_CURRENCY).available,
+            Decimal::from(DEPOSIT_AMOUNT * INIT_DEPOSIT_COUNT - 40)
+        );
+        assert_eq!(
+            client.add_transaction(txid, amount_tx(TransactionType::Resolve, None)),
+            Err(Error::AccountLocked)
+        );
+    }
+
+    fn withdraw(client: &mut Client, txid: TransactionID) {
+        client
+            .add_transaction(
+                txid,
+                Transaction::new(
+                    TransactionType::Withdrawal,
+                    Some(Decimal::from(DEPOSIT_AMOUNT)),
+                ),
+            )
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_disputes_and_resolves() {
+        let mut client = init();
+        let dispute_tx = Transaction::new(TransactionType::Dispute, None);
+        let resolve_tx = Transaction::new(TransactionType::Resolve, None);
+        let withdrawal_id = INIT_DEPOSIT_COUNT + 1;
+
+        withdraw(&mut client, withdrawal_id);
+
+        // Test dispute: the withdrawn amount is held, available is untouched
+        let prev_available = client.balance(NO_CURRENCY).available;
+        let prev_held = client.balance(NO_CURRENCY).held;
+
+        client
+            .add_transaction(withdrawal_id, dispute_tx.clone())
+            .unwrap();
+
+        assert_eq!(
+            client.balance(NO_CURRENCY).held,
+            prev_held + Decimal::from(DEPOSIT_AMOUNT)
+        );
+        assert_eq!(client.balance(NO_CURRENCY).available, prev_available);
+
+        // Test duplicate dispute
+        test_ignored(&mut client, withdrawal_id, dispute_tx.clone());
+
+        // Test resolve: the withdrawal stands, so the held amount is dropped
+        client
+            .add_transaction(withdrawal_id, resolve_tx.clone())
+            .unwrap();
+
+        assert_eq!(client.balance(NO_CURRENCY).held, prev_held);
+        assert_eq!(client.balance(NO_CURRENCY).available, prev_available);
+
+        // Test resolving again
+        test_ignored(&mut client, withdrawal_id, resolve_tx);
+
+        // Test disputing a failed withdrawal
+        let total = client.balance(NO_CURRENCY).available + client.balance(NO_CURRENCY).held;
+        test_ignored(
+            &mut client,
+            withdrawal_id + 1,
+            Transaction::new(TransactionType::Withdrawal, Some(total + Decimal::from(1))),
+        );
+        test_ignored(&mut client, withdrawal_id + 1, dispute_tx);
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_chargeback() {
+        let mut client = init();
+        let dispute_tx = Transaction::new(TransactionType::Dispute, None);
+        let chargeback_tx = Transaction::new(TransactionType::Chargeback, None);
+        let withdrawal_id = INIT_DEPOSIT_COUNT + 1;
+
+        withdraw(&mut client, withdrawal_id);
+        client
+            .add_transaction(withdrawal_id, dispute_tx.clone())
+            .unwrap();
+
+        // Test chargeback non existent id
+        test_ignored(&mut client, 100, chargeback_tx.clone());
+
+        // Test chargeback: the withdrawn amount is credited back
+        let prev_available = client.balance(NO_CURRENCY).available;
+        let prev_held = client.balance(NO_CURRENCY).held;
+
+        client
+            .add_transaction(withdrawal_id, chargeback_tx.clone())
+            .unwrap();
+
+        assert_eq!(
+            client.balance(NO_CURRENCY).held,
+            prev_held - Decimal::from(DEPOSIT_AMOUNT)
+        );
+        assert_eq!(
+            client.balance(NO_CURRENCY).available,
+            prev_available + Decimal::from(DEPOSIT_AMOUNT)
+        );
+        assert_eq!(
+            client.balance(NO_CURRENCY).available,
+            Decimal::from(DEPOSIT_AMOUNT * INIT_DEPOSIT_COUNT)
+        );
+        assert!(client.locked);
+
+        // Test chargeback same id [ it's locked, so it should be ignored]
+        test_ignored(&mut client, withdrawal_id, chargeback_tx);
+
+        // Test all other transation types [ it's locked, so they should all be ignored]
+        test_ignored(&mut client, withdrawal_id, dispute_tx);
+        test_ignored(
+            &mut client,
+            101,
+            Transaction::new(
+                TransactionType::Withdrawal,
+                Some(Decimal::from(DEPOSIT_AMOUNT)),
+            ),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_full_disputes() {
+        let mut client = init();
+        let withdrawal_tx = Transaction::new(
+            TransactionType::Withdrawal,
+            Some(Decimal::from(DEPOSIT_AMOUNT * INIT_DEPOSIT_COUNT)),
+        );
+        let dispute_tx = || Transaction::new(TransactionType::Dispute, None);
+        let policy = Policy {
+            disputes: DisputePolicy::Full,
+            ..Policy::default()
+        };
+
+        client.add_transaction(10, withdrawal_tx).unwrap();
+        test_ignored(&mut client, 0, dispute_tx());
+
+        client
+            .add_transaction_with(0, dispute_tx(), &policy)
+            .unwrap();
+        assert_eq!(
+            client.balance(NO_CURRENCY).available,
+            -Decimal::from(DEPOSIT_AMOUNT)
+        );
+        assert_eq!(
+            client.balance(NO_CURRENCY).held,
+            Decimal::from(DEPOSIT_AMOUNT)
+        );
+
+        // The exposure stays once charged back
+        client
+            .add_transaction_with(
+                0,
+                Transaction::new(TransactionType::Chargeback, None),
+                &policy,
+            )
+            .unwrap();
+        assert_eq!(
+            client.balance(NO_CURRENCY).available,
+            -Decimal::from(DEPOSIT_AMOUNT)
+        );
+        assert_eq!(client.balance(NO_CURRENCY).held, Decimal::from(0));
+        assert!(client.locked);
+    }
+
+    #[tokio::test]
+    async fn test_currencies() {
+        let mut client = Client::new(1);
+        let tx =
+            |tx_type: TransactionType, amount: Option<u32>, currency: Option<&str>| Transaction {
+                currency: currency.map(str::to_string),
+                ..Transaction::new(tx_type, amount.map(Decimal::from))
+            };
+
+        client
+            .add_transaction(1, tx(TransactionType::Deposit, Some(10), Some("EUR")))
+            .unwrap();
+        client
+            .add_transaction(2, tx(TransactionType::Deposit, Some(5), Some("USD")))
+            .unwrap();
+
+        // Funds in one currency can't be withdrawn in another
+        assert_eq!(
+            client.add_transaction(3, tx(TransactionType::Withdrawal, Some(6), Some("USD"))),
+            Err(Error::NoAvailableFunds)
+        );
+        client
+            .add_transaction(4, tx(TransactionType::Withdrawal, Some(6), Some("EUR")))
+            .unwrap();
+
+        // Disputes hold funds in the currency of the disputed deposit
+        client
+            .add_transaction(2, tx(TransactionType::Dispute, None, None))
+            .unwrap();
+        assert_eq!(
+            client.balance("USD"),
+            Balance {
+                available: Decimal::from(0),
+                held: Decimal::from(5),
+            }
+        );
+        assert_eq!(client.balance("EUR").available, Decimal::from(4));
+        assert_eq!(client.tx_currency(2), Some("USD"));
+
+        client
+            .add_transaction(2, tx(TransactionType::Chargeback, None, None))
+            .unwrap();
+        assert_eq!(client.balance("USD"), Balance::default());
+        assert_eq!(
+            client.balances().keys().collect::<Vec<_>>(),
+            vec!["EUR", "USD"]
+        );
+        assert_eq!(client.balance(NO_CURRENCY), Balance::default());
+    }
+
+    #[tokio::test]
+    async fn test_convert() {
+        let mut client = Client::new(1);
+        let mut policy = Policy::default();
+        policy.conversion.rates = RateTable::new()
+            .with_rate("EUR", "USD", Decimal::from_str("1.1").unwrap(), 100)
+            .with_rate("EUR", "USD", Decimal::from_str("1.23456").unwrap(), 200);
+        let tx = |tx_type: TransactionType, amount: &str, to: Option<&str>, at: Option<u64>| {
+            Transaction {
+                currency: Some("EUR".to_string()),
+                to_currency: to.map(str::to_string),
+                timestamp: at,
+                ..Transaction::new(tx_type, Some(Decimal::from_str(amount).unwrap()))
+            }
+        };
+
+        client
+            .add_transaction_with(1, tx(TransactionType::Deposit, "10", None, None), &policy)
+            .unwrap();
+
+        client
+            .add_transaction_with(
+                2,
+                tx(TransactionType::Convert, "2", Some("USD"), Some(150)),
+                &policy,
+            )
+            .unwrap();
+        assert_eq!(client.balance("EUR").available, Decimal::from(8));
+        assert_eq!(
+            client.balance("USD").available,
+            Decimal::from_str("2.2").unwrap()
+        );
+
+        // The latest rate, rounded half to even to 4 places
+        client
+            .add_transaction_with(
+                3,
+                tx(TransactionType::Convert, "1", Some("USD"), Some(250)),
+                &policy,
+            )
+            .unwrap();
+        assert_eq!(client.balance("EUR").available, Decimal::from(7));
+        assert_eq!(
+            client.balance("USD").available,
+            Decimal::from_str("3.4346").unwrap()
+        );
+
+        let rejected = [
+            (
+                tx(TransactionType::Convert, "1", Some("USD"), Some(50)),
+                Error::UnknownRate,
+            ),
+            (
+                tx(TransactionType::Convert, "1", Some("GBP"), Some(250)),
+                Error::UnknownRate,
+            ),
+            (
+                tx(TransactionType::Convert, "1", Some("USD"), None),
+                Error::MissingTimestamp,
+            ),
+            (
+                tx(TransactionType::Convert, "1", Some("EUR"), None),
+                Error::InvalidConversion,
+            ),
+            (
+                tx(TransactionType::Convert, "1", None, None),
+                Error::InvalidConversion,
+            ),
+            (
+                tx(TransactionType::Convert, "8", Some("USD"), Some(250)),
+                Error::NoAvailableFunds,
+            ),
+            (
+                tx(TransactionType::Convert, "-1", Some("USD"), Some(250)),
+                Error::InvalidAmount,
+            ),
+        ];
+        for (txid, (tx, error)) in (4..).zip(rejected) {
+            assert_eq!(client.add_transaction_with(txid, tx, &policy), Err(error));
+        }
+        assert_eq!(
+            client.add_transaction_with(
+                2,
+                tx(TransactionType::Convert, "1", Some("USD"), None),
+                &policy
+            ),
+            Err(Error::DuplicateTransaction)
+        );
+        assert_eq!(client.balance("EUR").available, Decimal::from(7));
+
+        // At the rate it carries, if any
+        client
+            .add_transaction_with(
+                10,
+                Transaction {
+                    rate: Some(Decimal::from(2)),
+                    ..tx(TransactionType::Convert, "1", Some("USD"), Some(250))
+                },
+                &policy,
+            )
+            .unwrap();
+        assert_eq!(client.balance("EUR").available, Decimal::from(6));
+        assert_eq!(
+            client.balance("USD").available,
+            Decimal::from_str("5.4346").unwrap()
+        );
+
+        // Conversions can't be disputed
+        assert_eq!(
+            client.add_transaction_with(2, tx(TransactionType::Dispute, "1", None, None), &policy),
+            Err(Error::TransactionNotFound)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transfer() {
+        let mut from = init();
+        let mut to = Client::new(2);
+        let transfer_tx =
+            |amount: u32| Transaction::new(TransactionType::Transfer, Some(Decimal::from(amount)));
+
+        from.transfer(&mut to, 10, transfer_tx(DEPOSIT_AMOUNT), &Policy::default())
+            .unwrap();
+        assert_eq!(
+            from.balance(NO_CURRENCY).available,
+            Decimal::from(DEPOSIT_AMOUNT * (INIT_DEPOSIT_COUNT - 1))
+        );
+        assert_eq!(
+            to.balance(NO_CURRENCY).available,
+            Decimal::from(DEPOSIT_AMOUNT)
+        );
+        // In the journals of both clients, which each add up to their balances
+        assert_eq!(to.posted(), from.posted());
+        for client in [&from, &to] {
+            assert_eq!(
+                client
+                    .journal_totals()
+                    .totals(NO_CURRENCY, Account::Available(client.id))
+                    .balance(),
+                client.balance(NO_CURRENCY).available
+            );
+        }
+
+        // Journals that only had it in the one of the sender get it back
+        let mut clients = [from.clone(), to.clone()];
+        clients[1].journal_totals.remove(&to.posted()[0]);
+        add_received_transfers(&mut clients);
+        assert_eq!(clients[0].journal_totals(), from.journal_totals());
+        assert_eq!(clients[1].journal_totals(), to.journal_totals());
+
+        // Only applied through `transfer`, which has both clients
+        test_ignored(&mut from, 11, transfer_tx(1));
+
+        from.locked = true;
+        assert_eq!(
+            from.transfer(&mut to, 12, transfer_tx(1), &Policy::default()),
+            Err(Error::AccountLocked)
+        );
+        let policy = Policy {
+            lock: LockPolicy::new().allow(TransactionType::Transfer),
+            ..Policy::default()
+        };
+        from.transfer(&mut to, 12, transfer_tx(1), &policy).unwrap();
+        assert_eq!(
+            to.balance(NO_CURRENCY).available,
+            Decimal::from(DEPOSIT_AMOUNT + 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fees() {
+        let mut client = init();
+        let policy = Policy {
+            fees: FeeSchedule::new()
+                .with_fee(
+                    TransactionType::Withdrawal,
+                    Fee {
+                        flat: Decimal::from(1),
+                        percentage: Decimal::from(10),
+                        min: None,
+                        max: Some(Decimal::from(5)),
+                    },
+                )
+                .with_fee(TransactionType::Chargeback, Fee::flat(Decimal::from(15))),
+            ..Policy::default()
+        };
+        let tx = |tx_type: TransactionType, amount: Option<u32>| {
+            Transaction::new(tx_type, amount.map(Decimal::from))
+        };
+        let available = |client: &Client| client.balance(NO_CURRENCY).available;
+
+        // 1 + 10% of 20
+        client
+            .add_transaction_with(10, tx(TransactionType::Withdrawal, Some(20)), &policy)
+            .unwrap();
+        assert_eq!(available(&client), Decimal::from(277));
+
+        // The fee must be available too
+        assert_eq!(
+            client.add_transaction_with(11, tx(TransactionType::Withdrawal, Some(273)), &policy),
+            Err(Error::NoAvailableFunds)
+        );
+        client
+            .add_transaction_with(12, tx(TransactionType::Withdrawal, Some(72)), &policy)
+            .unwrap();
+        assert_eq!(available(&client), Decimal::from(200));
+
+        client
+            .add_transaction_with(13, tx(TransactionType::Fee, Some(2)), &policy)
+            .unwrap();
+        assert_eq!(
+            client.add_transaction_with(14, tx(TransactionType::Fee, None), &policy),
+            Err(Error::InvalidAmount)
+        );
+        assert_eq!(available(&client), Decimal::from(198));
+
+        // The chargeback fee is charged even without the funds for it
+        client
+            .add_transaction_with(0, tx(TransactionType::Dispute, None), &policy)
+            .unwrap();
+        client
+            .add_transaction_with(15, tx(TransactionType::Withdrawal, Some(90)), &policy)
+            .unwrap();
+        client
+            .add_transaction_with(0, tx(TransactionType::Chargeback, None), &policy)
+            .unwrap();
+        assert_eq!(available(&client), Decimal::from(-12));
+        assert_eq!(
+            client.fees(),
+            &FeesMap::from([(NO_CURRENCY.to_string(), Decimal::from(30))])
+        );
+
+        // Charged back in parts, the flat fee is only charged once
+        let mut client = Client::new(2);
+        client
+            .add_transaction_with(1, tx(TransactionType::Deposit, Some(100)), &policy)
+            .unwrap();
+        client
+            .add_transaction_with(1, tx(TransactionType::Dispute, None), &policy)
+            .unwrap();
+        for amount in [40, 60] {
+            client
+                .add_transaction_with(1, tx(TransactionType::Chargeback, Some(amount)), &policy)
+                .unwrap();
+        }
+        assert_eq!(available(&client), Decimal::from(-15));
+        assert_eq!(
+            client.fees(),
+            &FeesMap::from([(NO_CURRENCY.to_string(), Decimal::from(15))])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_undo() {
+        let mut client = init();
+        let policy = Policy {
+            fees: FeeSchedule::new().with_fee(TransactionType::Chargeback, Fee::flat(Decimal::ONE)),
+            ..Policy::default()
+        };
+        client
+            .add_transaction(1, Transaction::new(TransactionType::Dispute, None))
+            .unwrap();
+        let freeze = Transaction {
+            reason: Some("fraud".to_string()),
+            ..Transaction::new(TransactionType::Freeze, None)
+        };
+
+        for (txid, tx) in [
+            (
+                100,
+                Transaction::new(TransactionType::Deposit, Some(5.into())),
+            ),
+            (
+                101,
+                Transaction::new(TransactionType::Withdrawal, Some(5.into())),
+            ),
+            (
+                2,
+                Transaction::new(TransactionType::Dispute, Some(5.into())),
+            ),
+            (1, Transaction::new(TransactionType::Resolve, None)),
+            (
+                1,
+                Transaction::new(TransactionType::Chargeback, Some(40.into())),
+            ),
+            (102, freeze),
+        ] {
+            let before = client.clone();
+            let checkpoint = client.checkpoint(txid);
+            client.add_transaction_with(txid, tx, &policy).unwrap();
+            client.undo(checkpoint);
+
+            assert_eq!(client.locked, before.locked);
+            assert_eq!(client.balances(), before.balances());
+            assert_eq!(client.fees(), before.fees());
+            assert_eq!(client.history(), before.history());
+            assert_eq!(client.journal_totals(), before.journal_totals());
+            assert_eq!(client.has_seen(txid), before.has_seen(txid));
+            assert_eq!(client.dispute_state(txid), before.dispute_state(txid));
+            assert_eq!(
+                client.stored_transaction(txid).is_some(),
+                before.stored_transaction(txid).is_some()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_journal() {
+        let mut client = Client::new(1);
+        let policy = Policy {
+            fees: FeeSchedule::new().with_fee(TransactionType::Withdrawal, Fee::flat(Decimal::ONE)),
+            ..Policy::default()
+        };
+        let tx = |tx_type: TransactionType, amount: Option<u32>| {
+            Transaction::new(tx_type, amount.map(Decimal::from))
+        };
+        let entry = |tx, debit, credit, amount| JournalEntry {
+            tx: Some(tx),
+            currency: NO_CURRENCY.to_string(),
+            debit,
+            credit,
+            amount: Decimal::from(amount),
+        };
+
+        let transactions = [
+            (1, tx(TransactionType::Deposit, Some(10))),
+            (2, tx(TransactionType::Deposit, Some(5))),
+            (3, tx(TransactionType::Withdrawal, Some(3))),
+            (3, tx(TransactionType::Dispute, None)),
+            (3, tx(TransactionType::Resolve, None)),
+            (1, tx(TransactionType::Dispute, None)),
+            (1, tx(TransactionType::Chargeback, None)),
+        ];
+        let mut journal = vec![];
+        for (txid, tx) in transactions {
+            client.add_transaction_with(txid, tx, &policy).unwrap();
+            journal.extend_from_slice(client.posted());
+        }
+        // Rejected transactions post nothing
+        assert!(client
+            .add_transaction_with(4, tx(TransactionType::Withdrawal, Some(100)), &policy)
+            .is_err());
+        assert_eq!(client.posted(), &[]);
+
+        let (available, held) = (Account::Available(1), Account::Held(1));
+        assert_eq!(
+            journal,
+            [
+                entry(1, available, Account::Funding, 10),
+                entry(2, available, Account::Funding, 5),
+                entry(3, Account::Funding, available, 3),
+                entry(3, Account::Revenue, available, 1),
+                entry(3, held, Account::Funding, 3),
+                entry(3, Account::Funding, held, 3),
+                entry(1, held, available, 10),
+                entry(1, Account::ChargebackLoss, held, 10),
+            ]
+        );
+        assert_eq!(client.balance(NO_CURRENCY).available, Decimal::ONE);
+
+        let mut totals = TrialBalance::new();
+        journal.iter().for_each(|entry| totals.add(entry));
+        assert_eq!(client.journal_totals(), &totals);
+        assert_eq!(
+            Client::from(ClientState::from(&client)).journal_totals(),
+            &totals
+        );
+
+        // Snapshots of version 3 have the whole journal instead
+        let mut state = ClientState::from(&client);
+        (state.journal_totals, state.journal) = (None, Some(journal));
+        assert_eq!(Client::from(state).journal_totals(), &totals);
+
+        // Snapshots taken before journals existed get their balances from the opening account
+        let mut state = ClientState::from(&init());
+        state.journal_totals = None;
+        let restored = Client::from(state);
+        assert_eq!(
+            restored.posted(),
+            &[JournalEntry {
+                tx: None,
+                ..entry(
+                    0,
+                    available,
+                    Account::Opening,
+                    DEPOSIT_AMOUNT * INIT_DEPOSIT_COUNT
+                )
+            }]
+        );
+        assert_eq!(
+            Client::from(ClientState::from(&restored)).journal_totals(),
+            restored.journal_totals()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_freeze_and_unlock() {
+        let mut client = init();
+        let admin_tx = |tx_type: TransactionType, reason: Option<&str>| Transaction {
+            reason: reason.map(str::to_string),
+            ..Transaction::new(tx_type, None)
+        };
+        let deposit_tx = || {
+            Transaction::new(
+                TransactionType::Deposit,
+                Some(Decimal::from(DEPOSIT_AMOUNT)),
+            )
+        };
+
+        assert_eq!(
+            client.add_transaction(10, admin_tx(TransactionType::Unlock, Some("ok"))),
+            Err(Error::AccountNotLocked)
+        );
+        assert_eq!(
+            client.add_transaction(10, admin_tx(TransactionType::Freeze, Some(" "))),
+            Err(Error::MissingReason)
+        );
+
+        client
+            .add_transaction(10, admin_tx(TransactionType::Freeze, Some("fraud-review")))
+            .unwrap();
+        assert!(client.locked);
+        test_ignored(&mut client, 11, deposit_tx());
+        assert_eq!(
+            client.add_transaction(12, admin_tx(TransactionType::Freeze, Some("again"))),
+            Err(Error::AccountLocked)
+        );
+
+        // Unlock ids are unique like any other transaction
+        assert_eq!(
+            client.add_transaction(10, admin_tx(TransactionType::Unlock, Some("cleared"))),
+            Err(Error::DuplicateTransaction)
+        );
+        client
+            .add_transaction(13, admin_tx(TransactionType::Unlock, Some("cleared")))
+            .unwrap();
+        assert!(!client.locked);
+        client.add_transaction(14, deposit_tx()).unwrap();
+
+        assert_eq!(
+            client.history(),
+            &[
+                AdminAction {
+                    tx: 10,
+                    tx_type: TransactionType::Freeze,
+                    reason: "fraud-review".to_string(),
+                },
+                AdminAction {
+                    tx: 13,
+                    tx_type: TransactionType::Unlock,
+                    reason: "cleared".to_string(),
+                },
+            ]
         );
     }
 
     #[tokio::test]
     async fn test_serialize() {
+        let balance = |available: &str, held: &str| Balance {
+            available: Decimal::from_str(available).unwrap(),
+            held: Decimal::from_str(held).unwrap(),
+        };
         let client = Client {
-            available: Decimal::from_str("2.1234220").unwrap(),
-            held: Decimal::from_str("2.00006").unwrap(),
             locked: true,
             id: 1,
+            insertion_order: 0,
+            balances: BalancesMap::from([
+                ("USD".to_string(), balance("1", "0")),
+                ("EUR".to_string(), balance("2.1234220", "2.00006")),
+            ]),
             deposits: TransactionsMap::new(),
-            disputed_deposit_ids: UniqueTransactionIDs::new(),
+            withdrawals: TransactionsMap::new(),
+            disputes: DisputesMap::new(),
             seen_transaction_ids: UniqueTransactionIDs::new(),
+            history: vec![],
+            fees: FeesMap::new(),
+            journal_totals: TrialBalance::new(),
+            posted: vec![],
         };
-        let compare_data = "client,available,held,total,locked\n1,2.1234,2.0001,4.1235,true\n";
+        let compare_data = "client,currency,available,held,total,locked\n1,EUR,2.1234,2.0001,4.1235,true\n1,USD,1,0.0000,1,true\n";
 
         let mut writer = csv::Writer::from_writer(Vec::new());
-        writer.serialize(client).unwrap();
+        for row in SerializableClient::rows(&client) {
+            writer.serialize(row).unwrap();
+        }
 
         assert_eq!(
             String::from_utf8(writer.into_inner().unwrap()).unwrap(),
             compare_data
         );
+
+        // A client without funds still has a row
+        assert_eq!(
+            SerializableClient::rows(&Client::new(2)),
+            vec![SerializableClient::new(&Client::new(2), NO_CURRENCY)]
+        );
     }
 }
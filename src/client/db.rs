@@ -1,13 +1,22 @@
 use crate::client::{Client, ClientID};
+use crate::transaction::TransactionID;
 use std::collections::HashMap;
+use std::sync::Arc;
 use tokio::sync::RwLock;
 
 // When updating client data, we only want to read_lock the hashmap
-//    and write_lock the client
+//    (long enough to get the client) and write_lock the client
 // When adding a client, we want to write_lock the hashmap
 //    and add the client
-pub type ClientsDB = RwLock<HashMap<ClientID, RwLock<Client>>>;
+pub type ClientsDB = RwLock<HashMap<ClientID, Arc<RwLock<Client>>>>;
+
+// Owner of every deposit/withdrawal transaction ID, across all clients
+pub type TransactionsIndex = RwLock<HashMap<TransactionID, ClientID>>;
 
 pub fn generate_client_db() -> ClientsDB {
-    RwLock::new(HashMap::<ClientID, RwLock<Client>>::new())
+    RwLock::new(HashMap::<ClientID, Arc<RwLock<Client>>>::new())
+}
+
+pub fn generate_transactions_index() -> TransactionsIndex {
+    RwLock::new(HashMap::<TransactionID, ClientID>::new())
 }
@@ -1,56 +1,283 @@
-use crate::{error::Error, transaction::ParsedTransaction};
+use crate::{
+    audit::{AuditRecord, AuditWriter},
+    client::{
+        policy::{DisputePolicy, LockPolicy, Policy, Rounding},
+        storage::{read_client, Storage},
+        SerializableClient,
+    },
+    error::Error,
+    fees::FeeSchedule,
+    rates::RateTable,
+    transaction::{ParsedTransaction, TransactionType, NO_CURRENCY},
+    wal::WriteAheadLog,
+};
+use rust_decimal::Decimal;
 use std::sync::Arc;
-use tokio::sync::RwLock;
 
-use crate::client::db::ClientsDB;
+// State of the clients a transaction changed, right after it
+struct Applied {
+    account: SerializableClient,
+    // Of the recipient, for transfers
+    recipient: Option<SerializableClient>,
+}
 
+#[derive(Clone)]
 pub struct ClientsManager {
-    map: Arc<ClientsDB>,
+    storage: Arc<dyn Storage>,
+    wal: Option<Arc<WriteAheadLog>>,
+    audit: Option<Arc<AuditWriter>>,
+    policy: Arc<Policy>,
 }
 
 impl ClientsManager {
-    pub fn new(arc: Arc<ClientsDB>) -> ClientsManager {
-        ClientsManager { map: arc }
+    pub fn new(storage: Arc<dyn Storage>) -> ClientsManager {
+        ClientsManager {
+            storage,
+            wal: None,
+            audit: None,
+            policy: Arc::new(Policy::default()),
+        }
     }
 
-    pub async fn push_tx(&self, tx: ParsedTransaction) -> Result<(), Error> {
-        let tx_id = tx.tx_id;
-        let client_id = tx.client_id;
+    /// Logs every transaction to `wal` before it changes a client.
+    pub fn with_wal(mut self, wal: Arc<WriteAheadLog>) -> ClientsManager {
+        self.wal = Some(wal);
+        self
+    }
 
-        // If there is a client in the DB, add it
-        {
-            let db_read = self.map.read().await;
+    /// Records the outcome of every transaction to `audit`, along with the state of the
+    /// client after it.
+    pub fn with_audit(mut self, audit: Arc<AuditWriter>) -> ClientsManager {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Decides which transactions locked clients still accept.
+    pub fn with_lock_policy(mut self, policy: LockPolicy) -> ClientsManager {
+        Arc::make_mut(&mut self.policy).lock = policy;
+        self
+    }
+
+    /// Decides how much of a deposit disputes hold.
+    pub fn with_dispute_policy(mut self, policy: DisputePolicy) -> ClientsManager {
+        Arc::make_mut(&mut self.policy).disputes = policy;
+        self
+    }
+
+    /// Rates conversions are made at.
+    pub fn with_rates(mut self, rates: RateTable) -> ClientsManager {
+        Arc::make_mut(&mut self.policy).conversion.rates = rates;
+        self
+    }
+
+    /// How converted amounts are rounded.
+    pub fn with_rounding(mut self, rounding: Rounding) -> ClientsManager {
+        Arc::make_mut(&mut self.policy).conversion.rounding = rounding;
+        self
+    }
+
+    /// Fees charged on withdrawals and chargebacks.
+    pub fn with_fees(mut self, fees: FeeSchedule) -> ClientsManager {
+        Arc::make_mut(&mut self.policy).fees = fees;
+        self
+    }
+
+    pub async fn flush(&self) {
+        if let Some(audit) = &self.audit {
+            audit.flush().await;
+        }
+    }
+
+    // Transaction IDs are unique across all clients:
+    //    deposits/withdrawals (and freezes/unlocks/conversions/transfers/fees) claim their ID
+    //    for their client (the sender, for transfers),
+    //    disputes/resolves/chargebacks must come from the client owning the ID.
+    // Returns whether `tx` claimed its ID, which it gives up if it's rejected.
+    async fn check_tx_owner(&self, tx: &ParsedTransaction) -> Result<bool, Error> {
+        match tx.tx_type {
+            TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Freeze
+            | TransactionType::Unlock
+            | TransactionType::Convert
+            | TransactionType::Transfer
+            | TransactionType::Fee => {
+                match self.storage.claim_tx(tx.tx_id, tx.client_id).await? {
+                    None => Ok(true),
+                    // Rejected by the client, unless it's a transaction it didn't apply yet
+                    Some(owner) if owner == tx.client_id => Ok(false),
+                    Some(_) => Err(Error::DuplicateTransaction),
+                }
+            }
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                match self.storage.tx_owner(tx.tx_id).await {
+                    Some(owner) if owner != tx.client_id => Err(Error::TransactionClientMismatch),
+                    _ => Ok(false),
+                }
+            }
+        }
+    }
+
+    /// Applies `tx`, returning the state of its client right after it, in the currency
+    /// it moved funds in.
+    pub async fn push_tx(&self, tx: ParsedTransaction) -> Result<SerializableClient, Error> {
+        self.push_record(None, tx).await
+    }
+
+    /// Same as `push_tx`, for the record at `offset` of the input, if it was read from one.
+    pub async fn push_record(
+        &self,
+        offset: Option<u64>,
+        mut tx: ParsedTransaction,
+    ) -> Result<SerializableClient, Error> {
+        // The rate of a conversion is kept on it, so it's logged and audited with it
+        tx.rate = self.conversion_rate(&tx);
+        self.push_rated(offset, tx).await
+    }
+
+    /// Applies `tx` as it was logged to the write-ahead log: a conversion is made at the
+    /// rate it carries, not at the one of the current rates.
+    pub async fn replay(&self, tx: ParsedTransaction) -> Result<SerializableClient, Error> {
+        self.push_rated(None, tx).await
+    }
+
+    async fn push_rated(
+        &self,
+        offset: Option<u64>,
+        tx: ParsedTransaction,
+    ) -> Result<SerializableClient, Error> {
+        let audit = match &self.audit {
+            Some(audit) => audit,
+            None => return self.apply(offset, tx).await.map(|applied| applied.account),
+        };
 
-            if db_read.contains_key(&client_id) {
-                let mut client = db_read
-                    .get(&client_id)
-                    .ok_or(Error::FailedPushingTx)?
-                    .write()
+        let audited = tx.clone();
+        let result = self.apply(offset, tx).await;
+
+        match &result {
+            // Both clients of a transfer are audited, as they were right after it
+            Ok(applied) => {
+                let rate = audited.rate;
+                let account = Some(applied.account.clone());
+                audit
+                    .record(AuditRecord::new(&audited, &result, account, rate))
+                    .await;
+                if let Some(recipient) = &applied.recipient {
+                    audit
+                        .record(AuditRecord::recipient(&audited, recipient.clone()))
+                        .await;
+                }
+            }
+            // A rejected transaction didn't change its client, so it's read afterwards
+            Err(_) => {
+                let account = self.account_for(&audited).await;
+                audit
+                    .record(AuditRecord::new(&audited, &result, account, None))
                     .await;
-                client.add_transaction(tx_id, tx.extract_tx());
+            }
+        }
+
+        result.map(|applied| applied.account)
+    }
+
+    /// State of the client of `tx`, in the currency `tx` moves funds in: its own, or the
+    /// one of the transaction it disputes/resolves/charges back.
+    pub async fn account_for(&self, tx: &ParsedTransaction) -> Option<SerializableClient> {
+        read_client(&*self.storage, tx.client_id, |client| {
+            SerializableClient::after(client, tx.tx_id, &tx.tx_type, tx.currency.as_deref())
+        })
+        .await
+    }
+
+    /// Rate the conversion `tx` is made at, if it's one and there's a rate for it at its
+    /// timestamp.
+    pub fn conversion_rate(&self, tx: &ParsedTransaction) -> Option<Decimal> {
+        match (&tx.tx_type, &tx.to_currency, tx.timestamp) {
+            (TransactionType::Convert, Some(to), Some(at)) => self.policy.conversion.rates.rate(
+                tx.currency.as_deref().unwrap_or(NO_CURRENCY),
+                to,
+                Some(at),
+            ),
+            _ => None,
+        }
+    }
+
+    async fn apply(&self, offset: Option<u64>, tx: ParsedTransaction) -> Result<Applied, Error> {
+        let tx_id = tx.tx_id;
+        let client_id = tx.client_id;
 
-                return Ok(());
+        let claimed = match self.claim(&tx).await {
+            Ok(claimed) => claimed,
+            // Logged too, so recovering skips its record instead of checking it again
+            Err(e) => {
+                if let Some(wal) = &self.wal {
+                    wal.append_rejected(offset, &tx).await?;
+                }
+                return Err(e);
             }
+        };
+
+        let result = self.apply_claimed(offset, tx).await;
+
+        // A rejected transaction doesn't use up its ID
+        if claimed && result.is_err() {
+            self.storage.release_tx(tx_id, client_id).await?;
         }
 
+        result
+    }
+
+    // Checks `tx` can be applied by its client, claiming its ID (see `check_tx_owner`)
+    async fn claim(&self, tx: &ParsedTransaction) -> Result<bool, Error> {
+        // Only the client's own queue creates it, so it can't appear in between
+        if matches!(
+            tx.tx_type,
+            TransactionType::Freeze | TransactionType::Unlock
+        ) && read_client(&*self.storage, tx.client_id, |_| ())
+            .await
+            .is_none()
         {
-            let mut db_write = self.map.write().await;
-
-            // Check again, in case it got created right before we acquired the write_lock
-            if db_write.contains_key(&client_id) {
-                let mut client = db_write
-                    .get(&client_id)
-                    .ok_or(Error::FailedPushingTx)?
-                    .write()
-                    .await;
-                client.add_transaction(tx_id, tx.extract_tx());
-            } else {
-                let (tx_details, mut client) = tx.extract_tx_and_client();
-                client.add_transaction(tx_id, tx_details);
+            return Err(Error::ClientNotFound);
+        }
 
-                db_write.insert(client_id, RwLock::new(client));
+        self.check_tx_owner(tx).await
+    }
+
+    async fn apply_claimed(
+        &self,
+        offset: Option<u64>,
+        tx: ParsedTransaction,
+    ) -> Result<Applied, Error> {
+        // Logged after claiming the ID, so replaying the log claims IDs in the same order
+        if let Some(wal) = &self.wal {
+            wal.append(offset, &tx).await?;
+        }
+
+        let tx_id = tx.tx_id;
+        let client_id = tx.client_id;
+        match (&tx.tx_type, tx.to) {
+            // Changes two clients at once, which the storage locks together
+            (TransactionType::Transfer, Some(to)) => {
+                let (account, recipient) = self
+                    .storage
+                    .transfer(client_id, to, tx_id, tx.extract_tx(), &self.policy)
+                    .await?;
+                Ok(Applied {
+                    account,
+                    recipient: Some(recipient),
+                })
+            }
+            (TransactionType::Transfer, None) => Err(Error::InvalidTransfer),
+            _ => {
+                let account = self
+                    .storage
+                    .add_transaction(client_id, tx_id, tx.extract_tx(), &self.policy)
+                    .await?;
+                Ok(Applied {
+                    account,
+                    recipient: None,
+                })
             }
         }
-        Ok(())
     }
 }
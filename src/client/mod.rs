@@ -1,6 +1,10 @@
+#[allow(clippy::module_inception)]
 mod client;
 pub mod db;
 pub mod manager;
+pub mod policy;
 pub mod queue;
+pub mod sqlite;
+pub mod storage;
 
 pub use client::*;
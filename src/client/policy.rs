@@ -0,0 +1,178 @@
+use std::{collections::HashSet, str::FromStr};
+
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use crate::{error::Error, fees::FeeSchedule, rates::RateTable, transaction::TransactionType};
+
+/// Rules clients apply transactions with.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Policy {
+    pub lock: LockPolicy,
+    pub disputes: DisputePolicy,
+    pub conversion: ConversionPolicy,
+    pub fees: FeeSchedule,
+}
+
+/// Transaction types a locked client still accepts.
+///
+/// By default a locked client only accepts `unlock`, which is always allowed:
+/// it's the only way back once locked. Settling open disputes is always allowed too
+/// (see `Client::add_transaction_with`).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LockPolicy {
+    allowed: HashSet<TransactionType>,
+}
+
+impl LockPolicy {
+    pub fn new() -> LockPolicy {
+        LockPolicy::default()
+    }
+
+    /// Lets locked clients accept `tx_type`.
+    pub fn allow(mut self, tx_type: TransactionType) -> LockPolicy {
+        self.allowed.insert(tx_type);
+        self
+    }
+
+    pub fn allows(&self, tx_type: &TransactionType) -> bool {
+        *tx_type == TransactionType::Unlock || self.allowed.contains(tx_type)
+    }
+}
+
+/// Reads a comma separated list of transaction types, eg. `deposit,resolve,chargeback`.
+impl FromStr for LockPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .try_fold(LockPolicy::new(), |policy, name| {
+                TransactionType::from_name(name)
+                    .map(|tx_type| policy.allow(tx_type))
+                    .ok_or(Error::UnknownFormat)
+            })
+    }
+}
+
+/// How much of a deposit a dispute holds.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DisputePolicy {
+    /// Only up to what's still available: disputing funds that were already withdrawn
+    /// is rejected as `NoAvailableFunds`.
+    #[default]
+    Available,
+    /// Always the whole disputed amount, letting `available` go negative.
+    Full,
+}
+
+impl FromStr for DisputePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "available" => Ok(DisputePolicy::Available),
+            "full" => Ok(DisputePolicy::Full),
+            _ => Err(Error::UnknownFormat),
+        }
+    }
+}
+
+/// Rates conversions are made at, and how the converted amounts are rounded.
+///
+/// Without rates, every conversion is rejected as `UnknownRate`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConversionPolicy {
+    pub rates: RateTable,
+    pub rounding: Rounding,
+}
+
+/// Decimal places, and how to round to them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rounding {
+    pub places: u32,
+    pub strategy: RoundingStrategy,
+}
+
+impl Rounding {
+    pub fn round(&self, amount: Decimal) -> Decimal {
+        amount.round_dp_with_strategy(self.places, self.strategy)
+    }
+}
+
+/// Four places (the precision of amounts), rounding half to even.
+impl Default for Rounding {
+    fn default() -> Self {
+        Rounding {
+            places: 4,
+            strategy: RoundingStrategy::MidpointNearestEven,
+        }
+    }
+}
+
+/// Reads a strategy, optionally followed by the places, eg. `half-up` or `down:2`.
+/// Strategies are `half-even`, `half-up`, `half-down`, `down` (towards zero)
+/// and `up` (away from zero).
+impl FromStr for Rounding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (strategy, places) = match s.split_once(':') {
+            Some((strategy, places)) => (
+                strategy,
+                places.trim().parse().map_err(|_| Error::UnknownFormat)?,
+            ),
+            None => (s, Rounding::default().places),
+        };
+        let strategy = match strategy.trim() {
+            "half-even" => RoundingStrategy::MidpointNearestEven,
+            "half-up" => RoundingStrategy::MidpointAwayFromZero,
+            "half-down" => RoundingStrategy::MidpointTowardZero,
+            "down" => RoundingStrategy::ToZero,
+            "up" => RoundingStrategy::AwayFromZero,
+            _ => return Err(Error::UnknownFormat),
+        };
+
+        Ok(Rounding { places, strategy })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lock_policy() {
+        let policy = LockPolicy::default();
+        assert!(policy.allows(&TransactionType::Unlock));
+        assert!(!policy.allows(&TransactionType::Deposit));
+
+        let policy = LockPolicy::from_str("deposit, resolve,chargeback").unwrap();
+        assert!(policy.allows(&TransactionType::Deposit));
+        assert!(policy.allows(&TransactionType::Chargeback));
+        assert!(!policy.allows(&TransactionType::Withdrawal));
+        assert!(!policy.allows(&TransactionType::Dispute));
+
+        assert_eq!(LockPolicy::from_str(""), Ok(LockPolicy::new()));
+        assert_eq!(
+            LockPolicy::from_str("deposit,refund"),
+            Err(Error::UnknownFormat)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rounding() {
+        let amount = Decimal::from_str("1.23455").unwrap();
+        let round = |rounding: &str| Rounding::from_str(rounding).unwrap().round(amount);
+
+        assert_eq!(Rounding::default().round(amount).to_string(), "1.2346");
+        assert_eq!(round("half-even").to_string(), "1.2346");
+        assert_eq!(round("half-down").to_string(), "1.2345");
+        assert_eq!(round("down:2").to_string(), "1.23");
+        assert_eq!(round("up:2").to_string(), "1.24");
+        assert_eq!(round("half-up:0").to_string(), "1");
+
+        assert_eq!(Rounding::from_str("nearest"), Err(Error::UnknownFormat));
+        assert_eq!(Rounding::from_str("down:a"), Err(Error::UnknownFormat));
+    }
+}
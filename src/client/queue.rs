@@ -1,131 +1,283 @@
 use futures::future::poll_fn;
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{hash_map::Entry, HashMap};
+use std::sync::{Arc, Mutex};
 use tokio::sync::{
     mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-    RwLock,
+    oneshot, RwLock,
 };
 
 use super::manager::ClientsManager;
-use crate::transaction::ParsedTransaction;
-use crate::{client::ClientID, error::Error};
+use crate::rejections::{Rejection, RejectionsWriter};
+use crate::transaction::{ParsedTransaction, TransactionID, TransactionType};
+use crate::{
+    client::{ClientID, SerializableClient},
+    error::Error,
+};
+
+/// A parsed transaction, along with the row it was read from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedTransaction {
+    pub row: u64,
+    /// Name of the input the record was read from (eg. its path), if any.
+    pub source: Option<Arc<str>>,
+    /// Position of the record in the whole input (across all files), used to resume it.
+    /// Assigned by `wal::ResumedSource`, and `None` for transactions that don't come from
+    /// an input (eg. submitted to the server).
+    pub offset: Option<u64>,
+    pub tx: ParsedTransaction,
+}
+
+impl QueuedTransaction {
+    pub fn new(row: u64, tx: ParsedTransaction) -> QueuedTransaction {
+        QueuedTransaction {
+            row,
+            source: None,
+            offset: None,
+            tx,
+        }
+    }
+
+    pub fn with_source(mut self, source: &Arc<str>) -> QueuedTransaction {
+        self.source = Some(Arc::clone(source));
+        self
+    }
+}
+
+pub type CQSenders = RwLock<HashMap<ClientID, Arc<RwLock<UnboundedSender<QueuedTransaction>>>>>;
+pub type CQReceivers = RwLock<HashMap<ClientID, Arc<RwLock<UnboundedReceiver<QueuedTransaction>>>>>;
 
-pub type CQSenders = RwLock<HashMap<ClientID, Arc<RwLock<UnboundedSender<ParsedTransaction>>>>>;
-pub type CQReceivers = RwLock<HashMap<ClientID, Arc<RwLock<UnboundedReceiver<ParsedTransaction>>>>>;
+// Whoever is waiting for the result of a queued transaction (the state of its client right
+//    after it, if applied), by row
+pub type Replies = Mutex<HashMap<u64, oneshot::Sender<Result<SerializableClient, Error>>>>;
+
+// A transfer, by sender, recipient and id
+type TransferKey = (ClientID, ClientID, TransactionID);
+
+/// Transfers that reached the front of one of their two queues, by the client whose queue
+/// they stopped until they reach the front of the other one.
+pub type Transfers = Mutex<HashMap<ClientID, TransferKey>>;
 
 pub fn generate_clients_queues() -> (CQSenders, CQReceivers) {
     let senders = RwLock::new(HashMap::<
         ClientID,
-        Arc<RwLock<UnboundedSender<ParsedTransaction>>>,
+        Arc<RwLock<UnboundedSender<QueuedTransaction>>>,
     >::new());
     let receivers = RwLock::new(HashMap::<
         ClientID,
-        Arc<RwLock<UnboundedReceiver<ParsedTransaction>>>,
+        Arc<RwLock<UnboundedReceiver<QueuedTransaction>>>,
     >::new());
 
     (senders, receivers)
 }
 
+// Clients whose queue `tx` goes through: its own, and the recipient's for a transfer,
+//    so it's ordered with the transactions of both
+fn queues_of(tx: &ParsedTransaction) -> Vec<ClientID> {
+    match (&tx.tx_type, tx.to) {
+        (TransactionType::Transfer, Some(to)) if to != tx.client_id => vec![tx.client_id, to],
+        _ => vec![tx.client_id],
+    }
+}
+
 async fn push_without_adding(
-    senders: &Arc<CQSenders>,
-    tx: &ParsedTransaction,
+    senders: &HashMap<ClientID, Arc<RwLock<UnboundedSender<QueuedTransaction>>>>,
+    client_id: ClientID,
+    tx: &QueuedTransaction,
 ) -> Result<bool, Error> {
-    let client_id = tx.client_id;
-    let readable_pile = senders.read().await;
-    if readable_pile.contains_key(&client_id) {
-        match readable_pile.get(&client_id) {
-            Some(sender) => match sender.write().await.send(tx.clone()) {
-                Ok(_) => Ok(true),
-                Err(_) => Err(Error::FailedPushingTx),
-            },
-            None => Ok(false),
-        }
-    } else {
-        Ok(false)
+    match senders.get(&client_id) {
+        Some(sender) => match sender.write().await.send(tx.clone()) {
+            Ok(_) => Ok(true),
+            Err(_) => Err(Error::FailedPushingTx),
+        },
+        None => Ok(false),
     }
 }
 
+async fn add_queue(senders: &Arc<CQSenders>, receivers: &Arc<CQReceivers>, client_id: ClientID) {
+    let (client_sender, client_receiver): (
+        UnboundedSender<QueuedTransaction>,
+        UnboundedReceiver<QueuedTransaction>,
+    ) = unbounded_channel();
+
+    let mut s = senders.write().await;
+    let mut r = loop {
+        match receivers.try_write() {
+            Ok(v) => break v,
+            Err(_) => continue,
+        };
+    };
+
+    // Check again, in case another pusher created it before we acquired the write_lock
+    if let Entry::Vacant(entry) = s.entry(client_id) {
+        entry.insert(Arc::new(RwLock::new(client_sender)));
+        r.insert(client_id, Arc::new(RwLock::new(client_receiver)));
+    }
+}
+
+/// Queues `tx` in the queue of its client, and of the recipient for transfers.
+/// Returns the clients whose queue it went to, to notify.
 pub async fn push_tx(
     senders: &Arc<CQSenders>,
     receivers: &Arc<CQReceivers>,
-    tx: ParsedTransaction,
-) {
-    let client_id = tx.client_id;
-    let added: bool = match push_without_adding(senders, &tx).await {
-        Ok(result) => result,
-        Err(_) => return, // ignore it
-    };
+    tx: QueuedTransaction,
+) -> Vec<ClientID> {
+    let clients = queues_of(&tx.tx);
+    for &client_id in &clients {
+        if !senders.read().await.contains_key(&client_id) {
+            add_queue(senders, receivers, client_id).await;
+        }
+    }
 
-    if !added {
-        let (client_sender, client_receiver): (
-            UnboundedSender<ParsedTransaction>,
-            UnboundedReceiver<ParsedTransaction>,
-        ) = unbounded_channel();
+    // Both legs of a transfer are queued while no other transaction can be, so transfers
+    //    are in the same order in every queue, and can't wait on each other
+    let mut pushed = Ok(true);
+    if clients.len() > 1 {
+        let senders = senders.write().await;
+        for &client_id in &clients {
+            pushed = pushed.and(push_without_adding(&senders, client_id, &tx).await);
+        }
+    } else {
+        pushed = push_without_adding(&*senders.read().await, clients[0], &tx).await;
+    }
 
-        {
-            let mut s = senders.write().await;
-            let mut r = loop {
-                match receivers.try_write() {
-                    Ok(v) => break v,
-                    Err(_) => continue,
-                };
-            };
+    match pushed {
+        Ok(_) => clients,
+        Err(_) => vec![], // ignore it
+    }
+}
 
-            s.insert(client_id, Arc::new(RwLock::new(client_sender)));
-            r.insert(client_id, Arc::new(RwLock::new(client_receiver)));
-        }
+// Where the front of the queue of `client_id` is, if it's `tx`
+enum Leg {
+    // Not a transfer between two queues
+    Only,
+    // The first of the two queues of the transfer to reach it, which stops until the other
+    //    one does too
+    First,
+    // The other queue already reached it: it's applied, and only then the other queue
+    //    resumed (see `resume`)
+    Last(ClientID),
+}
+
+fn leg(transfers: &Transfers, client_id: ClientID, tx: &ParsedTransaction) -> Leg {
+    let to = match queues_of(tx).as_slice() {
+        [_, to] => *to,
+        _ => return Leg::Only,
+    };
+    let key = (tx.client_id, to, tx.tx_id);
+    let other = if client_id == tx.client_id {
+        to
+    } else {
+        tx.client_id
+    };
+
+    let mut transfers = match transfers.lock() {
+        Ok(transfers) => transfers,
+        Err(_) => return Leg::Only,
+    };
+    if transfers.get(&other) == Some(&key) {
+        Leg::Last(other)
+    } else {
+        transfers.insert(client_id, key);
+        Leg::First
+    }
+}
 
-        push_without_adding(senders, &tx).await.ok();
+fn resume(transfers: &Transfers, client_id: ClientID) {
+    if let Ok(mut transfers) = transfers.lock() {
+        transfers.remove(&client_id);
     }
 }
 
+fn stopped(transfers: &Transfers, client_id: ClientID) -> bool {
+    transfers
+        .lock()
+        .is_ok_and(|transfers| transfers.contains_key(&client_id))
+}
+
 pub async fn consume(
     receivers: &Arc<CQReceivers>,
     client_id: ClientID,
     clients_manager: &ClientsManager,
+    rejections: &Option<Arc<RejectionsWriter>>,
+    replies: &Option<Arc<Replies>>,
+    transfers: &Arc<Transfers>,
 ) {
-    // Get this client specific channel receiver
-    let receiver = {
-        let receivers = receivers.clone();
-
-        let receivers = loop {
-            match receivers.try_read() {
-                Ok(v) => break v,
-                Err(_) => continue,
+    // Queues stopped by a transfer that was applied from this one, to carry on with
+    let mut resumed = vec![client_id];
+
+    while let Some(client_id) = resumed.pop() {
+        // Get this client specific channel receiver
+        let receiver = {
+            let receivers = receivers.clone();
+
+            let receivers = loop {
+                match receivers.try_read() {
+                    Ok(v) => break v,
+                    Err(_) => continue,
+                };
             };
+
+            receivers.get(&client_id).cloned()
         };
 
-        if receivers.contains_key(&client_id) {
-            match receivers.get(&client_id) {
-                Some(recv) => Some(recv.clone()),
-                None => None,
-            }
-        } else {
-            None
-        }
-    };
+        if let Some(receiver) = receiver {
+            loop {
+                let mut recv = receiver.write().await;
+                if stopped(transfers, client_id) {
+                    break;
+                }
 
-    match receiver {
-        Some(receiver) => loop {
-            let mut recv = receiver.write().await;
-
-            match poll_fn(|cx| match recv.poll_recv(cx) {
-                std::task::Poll::Ready(v) => std::task::Poll::Ready(v),
-                std::task::Poll::Pending => std::task::Poll::Ready(None),
-            })
-            .await
-            {
-                Some(tx) => match clients_manager.push_tx(tx).await {
-                    Ok(_) => (),
-                    Err(_e) => {
-                        // debug!("Error pushing parsed transaction: {}", _e);
+                match poll_fn(|cx| match recv.poll_recv(cx) {
+                    std::task::Poll::Ready(v) => std::task::Poll::Ready(v),
+                    std::task::Poll::Pending => std::task::Poll::Ready(None),
+                })
+                .await
+                {
+                    Some(queued) => match leg(transfers, client_id, &queued.tx) {
+                        Leg::Only => apply(queued, clients_manager, rejections, replies).await,
+                        Leg::First => break,
+                        Leg::Last(other) => {
+                            apply(queued, clients_manager, rejections, replies).await;
+                            resume(transfers, other);
+                            resumed.push(other);
+                        }
+                    },
+                    None => {
+                        break;
                     }
-                },
-                None => {
-                    break;
                 }
             }
-        },
-        None => {}
-    };
+        }
+    }
+}
+
+async fn apply(
+    queued: QueuedTransaction,
+    clients_manager: &ClientsManager,
+    rejections: &Option<Arc<RejectionsWriter>>,
+    replies: &Option<Arc<Replies>>,
+) {
+    let QueuedTransaction {
+        row,
+        source,
+        offset,
+        tx,
+    } = queued;
+    let rejected = rejections.as_ref().map(|_| tx.clone());
+
+    let result = clients_manager.push_record(offset, tx).await;
+    if let Err(e) = &result {
+        // debug!("Error pushing parsed transaction: {}", e);
+        if let (Some(rejections), Some(tx)) = (rejections, rejected) {
+            let rejection = Rejection::new(row, &tx, e).with_source(source);
+            rejections.record(rejection).await;
+        }
+    }
+
+    let reply = replies
+        .as_ref()
+        .and_then(|replies| replies.lock().ok()?.remove(&row));
+    if let Some(reply) = reply {
+        reply.send(result).ok();
+    }
 }
@@ -0,0 +1,1043 @@
+use async_trait::async_trait;
+use rusqlite::{params, Connection};
+use rust_decimal::Decimal;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::RwLock;
+
+use crate::{
+    client::{
+        policy::Policy,
+        storage::{transferred, MemoryStorage, Storage},
+        Balance, BalancesMap, Client, ClientID, FeesMap, SerializableClient,
+    },
+    error::Error,
+    journal::{Account, JournalEntry},
+    transaction::{AdminAction, DisputeState, Transaction, TransactionID, TransactionType},
+};
+
+const PRAGMAS: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+";
+
+// Every change to the schema, in order. A database is at the version of the last one it
+//    applied (its `user_version`), and only applies the ones after it
+const MIGRATIONS: [&str; 6] = [
+    // 1: accounts in a single currency
+    "
+    CREATE TABLE accounts (
+        client INTEGER PRIMARY KEY,
+        available TEXT NOT NULL,
+        held TEXT NOT NULL,
+        locked INTEGER NOT NULL,
+        insertion_order INTEGER NOT NULL
+    );
+    -- Deposits and withdrawals, which can be disputed
+    CREATE TABLE transactions (
+        tx INTEGER PRIMARY KEY,
+        client INTEGER NOT NULL,
+        type TEXT NOT NULL,
+        amount TEXT
+    );
+    CREATE TABLE disputes (
+        tx INTEGER PRIMARY KEY,
+        client INTEGER NOT NULL,
+        disputed TEXT NOT NULL,
+        charged_back TEXT NOT NULL
+    );
+    -- Transaction IDs already used by each client
+    CREATE TABLE seen_transactions (
+        tx INTEGER NOT NULL,
+        client INTEGER NOT NULL,
+        PRIMARY KEY (client, tx)
+    );
+    -- Owner of every deposit/withdrawal transaction ID, across all clients
+    CREATE TABLE claimed_transactions (
+        tx INTEGER PRIMARY KEY,
+        client INTEGER NOT NULL
+    );
+    ",
+    // 2: history of freezes and unlocks
+    "
+    -- Freezes and unlocks of every client, in the order they were applied
+    CREATE TABLE admin_actions (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        tx INTEGER NOT NULL UNIQUE,
+        client INTEGER NOT NULL,
+        type TEXT NOT NULL,
+        reason TEXT NOT NULL
+    );
+    ",
+    // 3: funds per currency, moving the existing ones to '' (no currency)
+    "
+    -- Funds of every client, per currency ('' for transactions without one)
+    CREATE TABLE balances (
+        client INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        available TEXT NOT NULL,
+        held TEXT NOT NULL,
+        PRIMARY KEY (client, currency)
+    );
+    INSERT INTO balances (client, currency, available, held)
+        SELECT client, '', available, held FROM accounts;
+
+    CREATE TABLE accounts_v2 (
+        client INTEGER PRIMARY KEY,
+        locked INTEGER NOT NULL,
+        insertion_order INTEGER NOT NULL
+    );
+    INSERT INTO accounts_v2 (client, locked, insertion_order)
+        SELECT client, locked, insertion_order FROM accounts;
+    DROP TABLE accounts;
+    ALTER TABLE accounts_v2 RENAME TO accounts;
+
+    ALTER TABLE transactions ADD COLUMN currency TEXT;
+    ",
+    // 4: fees
+    "
+    -- Fees every client paid to the house, per currency
+    CREATE TABLE fees (
+        client INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        amount TEXT NOT NULL,
+        PRIMARY KEY (client, currency)
+    );
+    ",
+    // 5: journals, opened with the balances the clients already have (see `load`)
+    "
+    -- Journal of every client, in the order its entries were posted
+    CREATE TABLE journal (
+        client INTEGER NOT NULL,
+        seq INTEGER NOT NULL,
+        tx INTEGER,
+        currency TEXT NOT NULL,
+        debit TEXT NOT NULL,
+        credit TEXT NOT NULL,
+        amount TEXT NOT NULL,
+        PRIMARY KEY (client, seq)
+    );
+    ",
+    // 6: transfers in the journal of their recipient too, after its own entries
+    "
+    INSERT INTO journal (client, seq, tx, currency, debit, credit, amount)
+    SELECT recipient,
+        (SELECT COALESCE(MAX(seq) + 1, 0) FROM journal AS own WHERE own.client = recipient)
+            + ROW_NUMBER() OVER (PARTITION BY recipient ORDER BY client, seq) - 1,
+        tx, currency, debit, credit, amount
+    FROM (
+        SELECT *, CAST(SUBSTR(debit, 11) AS INTEGER) AS recipient
+        FROM journal
+        WHERE debit LIKE 'available:%' AND debit != 'available:' || client
+    );
+    ",
+];
+
+// Version of the migration that added journals
+const JOURNAL_VERSION: usize = 5;
+
+/// Keeps accounts, stored deposits/withdrawals and dispute state in a sqlite database,
+/// so they can be queried with SQL and survive restarts.
+///
+/// Clients are also kept in memory: every change is written through to the database
+/// (one sqlite transaction per ledger transaction), and the database is only read on `open`.
+/// Clients stay locked until their changes are committed, and the changes are undone if
+/// they can't be.
+pub struct SqliteStorage {
+    memory: MemoryStorage,
+    conn: Arc<Mutex<Connection>>,
+}
+
+fn decimal(value: String) -> rusqlite::Result<Decimal> {
+    Decimal::from_str(&value).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, e.into())
+    })
+}
+
+fn account(value: String) -> rusqlite::Result<Account> {
+    Account::from_str(&value)
+        .map_err(|_| rusqlite::Error::InvalidColumnType(0, value, rusqlite::types::Type::Text))
+}
+
+impl SqliteStorage {
+    /// Opens (or creates) the database at `path`, loading the clients already in it.
+    pub fn open(path: &str) -> Result<SqliteStorage, Error> {
+        let mut conn = Connection::open(path).map_err(|_| Error::UnknownFile)?;
+        conn.execute_batch(PRAGMAS)
+            .map_err(|_| Error::InvalidDatabase)?;
+
+        let memory = migrate(&mut conn)
+            .and_then(|version| SqliteStorage::load(&conn, version < JOURNAL_VERSION))
+            .map_err(|e| {
+                debug!("Failed loading {}: {:?}", path, e);
+                Error::InvalidDatabase
+            })?;
+
+        Ok(SqliteStorage {
+            memory,
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    // `open_journals` for databases that had no journals before being migrated
+    fn load(conn: &Connection, open_journals: bool) -> rusqlite::Result<MemoryStorage> {
+        let mut clients = HashMap::new();
+
+        let mut accounts = conn.prepare("SELECT client, locked, insertion_order FROM accounts")?;
+        let mut rows = accounts.query([])?;
+        while let Some(row) = rows.next()? {
+            let mut client = Client::new(row.get(0)?);
+            client.locked = row.get(1)?;
+            client.insertion_order = row.get(2)?;
+            clients.insert(client.id, client);
+        }
+
+        let mut balances =
+            conn.prepare("SELECT client, currency, available, held FROM balances")?;
+        let mut rows = balances.query([])?;
+        while let Some(row) = rows.next()? {
+            let client_id: ClientID = row.get(0)?;
+            let balance = Balance {
+                available: decimal(row.get(2)?)?,
+                held: decimal(row.get(3)?)?,
+            };
+            if let Some(client) = clients.get_mut(&client_id) {
+                client.restore_balance(row.get(1)?, balance);
+            }
+        }
+
+        let mut fees = conn.prepare("SELECT client, currency, amount FROM fees")?;
+        let mut rows = fees.query([])?;
+        while let Some(row) = rows.next()? {
+            let client_id: ClientID = row.get(0)?;
+            let amount = decimal(row.get(2)?)?;
+            if let Some(client) = clients.get_mut(&client_id) {
+                client.restore_fees(row.get(1)?, amount);
+            }
+        }
+
+        let mut transactions =
+            conn.prepare("SELECT tx, client, type, amount, currency FROM transactions")?;
+        let mut rows = transactions.query([])?;
+        while let Some(row) = rows.next()? {
+            let client_id: ClientID = row.get(1)?;
+            let tx_type: String = row.get(2)?;
+            let amount: Option<String> = row.get(3)?;
+            let tx_type = TransactionType::from_name(&tx_type).ok_or(
+                rusqlite::Error::InvalidColumnType(2, tx_type, rusqlite::types::Type::Text),
+            )?;
+            let tx = Transaction {
+                currency: row.get(4)?,
+                ..Transaction::new(tx_type, amount.map(decimal).transpose()?)
+            };
+            if let Some(client) = clients.get_mut(&client_id) {
+                client.restore_transaction(row.get(0)?, tx);
+            }
+        }
+
+        let mut disputes =
+            conn.prepare("SELECT tx, client, disputed, charged_back FROM disputes")?;
+        let mut rows = disputes.query([])?;
+        while let Some(row) = rows.next()? {
+            let client_id: ClientID = row.get(1)?;
+            let state = DisputeState {
+                disputed: decimal(row.get(2)?)?,
+                charged_back: decimal(row.get(3)?)?,
+            };
+            if let Some(client) = clients.get_mut(&client_id) {
+                client.restore_dispute(row.get(0)?, state);
+            }
+        }
+
+        let mut seen = conn.prepare("SELECT tx, client FROM seen_transactions")?;
+        let mut rows = seen.query([])?;
+        while let Some(row) = rows.next()? {
+            let client_id: ClientID = row.get(1)?;
+            if let Some(client) = clients.get_mut(&client_id) {
+                client.restore_seen(row.get(0)?);
+            }
+        }
+
+        let mut actions =
+            conn.prepare("SELECT tx, client, type, reason FROM admin_actions ORDER BY id")?;
+        let mut rows = actions.query([])?;
+        while let Some(row) = rows.next()? {
+            let client_id: ClientID = row.get(1)?;
+            let tx_type: String = row.get(2)?;
+            let action = AdminAction {
+                tx: row.get(0)?,
+                tx_type: TransactionType::from_name(&tx_type).ok_or(
+                    rusqlite::Error::InvalidColumnType(2, tx_type, rusqlite::types::Type::Text),
+                )?,
+                reason: row.get(3)?,
+            };
+            if let Some(client) = clients.get_mut(&client_id) {
+                client.restore_admin_action(action);
+            }
+        }
+
+        let mut journal = conn.prepare(
+            "SELECT client, tx, currency, debit, credit, amount FROM journal ORDER BY client, seq",
+        )?;
+        let mut rows = journal.query([])?;
+        while let Some(row) = rows.next()? {
+            let client_id: ClientID = row.get(0)?;
+            let entry = JournalEntry {
+                tx: row.get(1)?,
+                currency: row.get(2)?,
+                debit: account(row.get(3)?)?,
+                credit: account(row.get(4)?)?,
+                amount: decimal(row.get(5)?)?,
+            };
+            if let Some(client) = clients.get_mut(&client_id) {
+                client.restore_entry(&entry);
+            }
+        }
+
+        if open_journals {
+            let db_tx = conn.unchecked_transaction()?;
+            for client in clients.values_mut() {
+                let id = client.id;
+                write_journal(&db_tx, id, client.open_journal())?;
+            }
+            db_tx.commit()?;
+        }
+
+        let mut claimed = conn.prepare("SELECT tx, client FROM claimed_transactions")?;
+        let tx_index = claimed
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<HashMap<TransactionID, ClientID>>>()?;
+
+        let clients = clients
+            .into_iter()
+            .map(|(id, client)| (id, Arc::new(RwLock::new(client))))
+            .collect();
+
+        Ok(MemoryStorage::from_parts(
+            RwLock::new(clients),
+            RwLock::new(tx_index),
+        ))
+    }
+
+    // Writes what `tx_id` changed in the clients, all or nothing, along with the claim of
+    //    `tx_id` by the first one if `claim` (sqlite blocks, so it runs off the async workers)
+    async fn persist(
+        &self,
+        clients: Vec<ClientRows>,
+        tx_id: TransactionID,
+        claim: bool,
+    ) -> Result<(), Error> {
+        let conn = Arc::clone(&self.conn);
+
+        let written = tokio::task::spawn_blocking(move || -> rusqlite::Result<()> {
+            let mut conn = conn.lock().expect("sqlite connection lock poisoned");
+            let db_tx = conn.transaction()?;
+
+            if claim {
+                db_tx.execute(
+                    "INSERT OR IGNORE INTO claimed_transactions (tx, client) VALUES (?1, ?2)",
+                    params![tx_id, clients[0].id],
+                )?;
+            }
+            for client in &clients {
+                write_client(&db_tx, client, tx_id)?;
+            }
+
+            db_tx.commit()
+        })
+        .await;
+
+        match written {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => {
+                debug!("Failed storing tx {}: {:?}", tx_id, e);
+                Err(Error::FailedStoring)
+            }
+            Err(e) => {
+                debug!("Failed storing tx {}: {:?}", tx_id, e);
+                Err(Error::FailedStoring)
+            }
+        }
+    }
+
+    // Whether `client_id` claimed `tx_id`, which is stored along with the transaction
+    async fn claimed_by(&self, tx_id: TransactionID, client_id: ClientID) -> bool {
+        self.memory.tx_owner(tx_id).await == Some(client_id)
+    }
+}
+
+// Brings the database to the latest version, returning the one it was at
+fn migrate(conn: &mut Connection) -> rusqlite::Result<usize> {
+    let version = schema_version(conn)?;
+    if version > MIGRATIONS.len() {
+        // Written by a newer version, which may have changed anything
+        return Err(rusqlite::Error::InvalidQuery);
+    }
+
+    for (applied, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let db_tx = conn.transaction()?;
+        db_tx.execute_batch(migration)?;
+        db_tx.pragma_update(None, "user_version", applied + 1)?;
+        db_tx.commit()?;
+    }
+
+    Ok(version)
+}
+
+fn schema_version(conn: &Connection) -> rusqlite::Result<usize> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > 0 {
+        return Ok(version);
+    }
+
+    // Databases from before migrations were versioned are told apart by their tables
+    let has_table = |table: &str| -> rusqlite::Result<bool> {
+        conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            [table],
+            |row| row.get::<_, u32>(0),
+        )
+        .map(|count| count > 0)
+    };
+    let single_currency = conn
+        .prepare("SELECT name FROM pragma_table_info('accounts')")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?
+        .iter()
+        .any(|column| column == "available");
+
+    Ok(if !has_table("accounts")? {
+        0
+    } else if single_currency {
+        if has_table("admin_actions")? {
+            2
+        } else {
+            1
+        }
+    } else if has_table("journal")? {
+        5
+    } else if has_table("fees")? {
+        4
+    } else {
+        3
+    })
+}
+
+// What a transaction may have changed in a client (see `Client::checkpoint`), copied out
+//    of it so the client doesn't have to be
+struct ClientRows {
+    id: ClientID,
+    locked: bool,
+    insertion_order: usize,
+    balances: BalancesMap,
+    fees: FeesMap,
+    transaction: Option<Transaction>,
+    dispute: Option<DisputeState>,
+    admin_action: Option<AdminAction>,
+    seen: bool,
+    posted: Vec<JournalEntry>,
+}
+
+impl ClientRows {
+    fn new(client: &Client, tx_id: TransactionID) -> ClientRows {
+        ClientRows {
+            id: client.id,
+            locked: client.locked,
+            insertion_order: client.insertion_order,
+            balances: client.balances().clone(),
+            fees: client.fees().clone(),
+            transaction: client.stored_transaction(tx_id).cloned(),
+            dispute: client.dispute_state(tx_id).cloned(),
+            admin_action: client.admin_action(tx_id).cloned(),
+            seen: client.has_seen(tx_id),
+            posted: client.posted().to_vec(),
+        }
+    }
+}
+
+fn write_client(
+    db_tx: &rusqlite::Transaction,
+    client: &ClientRows,
+    tx_id: TransactionID,
+) -> rusqlite::Result<()> {
+    db_tx.execute(
+        "INSERT OR REPLACE INTO accounts (client, locked, insertion_order)
+         VALUES (?1, ?2, ?3)",
+        params![client.id, client.locked, client.insertion_order],
+    )?;
+
+    // A client only has a few currencies, so all of them are written
+    for (currency, balance) in &client.balances {
+        db_tx.execute(
+            "INSERT OR REPLACE INTO balances (client, currency, available, held)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                client.id,
+                currency,
+                balance.available.to_string(),
+                balance.held.to_string(),
+            ],
+        )?;
+    }
+
+    for (currency, amount) in &client.fees {
+        db_tx.execute(
+            "INSERT OR REPLACE INTO fees (client, currency, amount) VALUES (?1, ?2, ?3)",
+            params![client.id, currency, amount.to_string()],
+        )?;
+    }
+
+    // Stored transactions never change once added
+    if let Some(tx) = &client.transaction {
+        db_tx.execute(
+            "INSERT OR IGNORE INTO transactions (tx, client, type, amount, currency)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                tx_id,
+                client.id,
+                tx.tx_type.name(),
+                tx.amount.map(|amount| amount.to_string()),
+                tx.currency,
+            ],
+        )?;
+    }
+
+    match &client.dispute {
+        Some(state) => db_tx.execute(
+            "INSERT OR REPLACE INTO disputes (tx, client, disputed, charged_back)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                tx_id,
+                client.id,
+                state.disputed.to_string(),
+                state.charged_back.to_string(),
+            ],
+        )?,
+        None => db_tx.execute(
+            "DELETE FROM disputes WHERE tx = ?1 AND client = ?2",
+            params![tx_id, client.id],
+        )?,
+    };
+
+    if let Some(action) = &client.admin_action {
+        db_tx.execute(
+            "INSERT OR IGNORE INTO admin_actions (tx, client, type, reason)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![tx_id, client.id, action.tx_type.name(), action.reason],
+        )?;
+    }
+
+    if client.seen {
+        db_tx.execute(
+            "INSERT OR IGNORE INTO seen_transactions (tx, client) VALUES (?1, ?2)",
+            params![tx_id, client.id],
+        )?;
+    }
+
+    write_journal(db_tx, client.id, &client.posted)?;
+
+    Ok(())
+}
+
+// Appends `entries` to the journal of the client
+fn write_journal(
+    db_tx: &rusqlite::Transaction,
+    client_id: ClientID,
+    entries: &[JournalEntry],
+) -> rusqlite::Result<()> {
+    for entry in entries {
+        db_tx.execute(
+            "INSERT INTO journal (client, seq, tx, currency, debit, credit, amount)
+             VALUES (?1, (SELECT COALESCE(MAX(seq) + 1, 0) FROM journal WHERE client = ?1),
+                     ?2, ?3, ?4, ?5, ?6)",
+            params![
+                client_id,
+                entry.tx,
+                entry.currency,
+                entry.debit.to_string(),
+                entry.credit.to_string(),
+                entry.amount.to_string(),
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn tx_owner(&self, tx_id: TransactionID) -> Option<ClientID> {
+        self.memory.tx_owner(tx_id).await
+    }
+
+    async fn claim_tx(
+        &self,
+        tx_id: TransactionID,
+        client_id: ClientID,
+    ) -> Result<Option<ClientID>, Error> {
+        // Only stored once its transaction is, in the same sqlite transaction
+        self.memory.claim_tx(tx_id, client_id).await
+    }
+
+    async fn release_tx(&self, tx_id: TransactionID, client_id: ClientID) -> Result<(), Error> {
+        // Rejected transactions never stored their claim
+        self.memory.release_tx(tx_id, client_id).await
+    }
+
+    async fn add_transaction(
+        &self,
+        client_id: ClientID,
+        tx_id: TransactionID,
+        tx: Transaction,
+        policy: &Policy,
+    ) -> Result<SerializableClient, Error> {
+        let claimed = self.claimed_by(tx_id, client_id).await;
+        let (tx_type, currency) = (tx.tx_type.clone(), tx.currency.clone());
+
+        self.memory
+            .update_committed(
+                client_id,
+                tx_id,
+                |client| {
+                    client.add_transaction_with(tx_id, tx, policy).map(|_| {
+                        SerializableClient::after(client, tx_id, &tx_type, currency.as_deref())
+                    })
+                },
+                // Rejected transactions are stored too, as they may have created the client
+                |client, result| {
+                    let claim = claimed && result.is_ok();
+                    self.persist(vec![ClientRows::new(client, tx_id)], tx_id, claim)
+                },
+            )
+            .await?
+    }
+
+    async fn transfer(
+        &self,
+        from: ClientID,
+        to: ClientID,
+        tx_id: TransactionID,
+        tx: Transaction,
+        policy: &Policy,
+    ) -> Result<(SerializableClient, SerializableClient), Error> {
+        let claimed = self.claimed_by(tx_id, from).await;
+        let currency = tx.currency.clone();
+
+        self.memory
+            .update_pair_committed(
+                from,
+                to,
+                tx_id,
+                |from, to| {
+                    from.transfer(to, tx_id, tx, policy)
+                        .map(|_| transferred(from, to, currency.as_deref()))
+                },
+                |from, to, result| {
+                    let clients = vec![ClientRows::new(from, tx_id), ClientRows::new(to, tx_id)];
+                    self.persist(clients, tx_id, claimed && result.is_ok())
+                },
+            )
+            .await?
+    }
+
+    async fn visit_client(
+        &self,
+        client_id: ClientID,
+        f: &mut (dyn for<'c> FnMut(&'c Client) + Send),
+    ) -> bool {
+        self.memory.visit_client(client_id, f).await
+    }
+
+    async fn visit_clients(&self, f: &mut (dyn for<'c> FnMut(&'c Client) + Send)) {
+        self.memory.visit_clients(f).await
+    }
+
+    async fn tx_index(&self) -> Vec<(TransactionID, ClientID)> {
+        self.memory.tx_index().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::FeesMap,
+        journal::Totals,
+        ledger::Ledger,
+        transaction::{ParsedTransaction, NO_CURRENCY},
+    };
+    use rusqlite::OptionalExtension;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_sqlite_restart() {
+        let path = std::env::temp_dir().join(format!("pay-test-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        {
+            let ledger = Ledger::from_storage(Arc::new(SqliteStorage::open(path).unwrap()));
+            let eur = |tx: ParsedTransaction| ParsedTransaction {
+                currency: Some("EUR".to_string()),
+                ..tx
+            };
+            for tx in [
+                eur(ParsedTransaction::new(
+                    TransactionType::Deposit,
+                    1,
+                    1,
+                    Some(10.into()),
+                )),
+                ParsedTransaction::new(TransactionType::Deposit, 2, 2, Some(5.into())),
+                eur(ParsedTransaction::new(
+                    TransactionType::Withdrawal,
+                    1,
+                    3,
+                    Some(4.into()),
+                )),
+                ParsedTransaction::new(TransactionType::Dispute, 1, 1, Some(3.into())),
+                ParsedTransaction {
+                    to: Some(2),
+                    ..eur(ParsedTransaction::new(
+                        TransactionType::Transfer,
+                        1,
+                        4,
+                        Some(1.into()),
+                    ))
+                },
+                ParsedTransaction::new(TransactionType::Fee, 2, 6, Some(1.into())),
+            ] {
+                ledger.apply(tx).await.unwrap();
+            }
+            ledger.freeze(2, 5, "kyc").await.unwrap();
+        }
+
+        let conn = Connection::open(path).unwrap();
+        let held: Option<String> = conn
+            .query_row(
+                "SELECT held FROM balances WHERE client = 1 AND currency = 'EUR'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()
+            .unwrap();
+        assert_eq!(held.as_deref(), Some("3"));
+        let disputed: String = conn
+            .query_row("SELECT disputed FROM disputes WHERE tx = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(disputed, "3");
+        drop(conn);
+
+        // Disputes (and the currency they hold funds in), seen ids, transaction owners
+        //    both sides of transfers and fees survive the restart
+        let ledger = Ledger::from_storage(Arc::new(SqliteStorage::open(path).unwrap()));
+        assert_eq!(
+            ledger
+                .apply(ParsedTransaction::new(
+                    TransactionType::Deposit,
+                    2,
+                    3,
+                    Some(1.into())
+                ))
+                .await,
+            Err(Error::DuplicateTransaction)
+        );
+        let account = ledger
+            .apply(ParsedTransaction::new(
+                TransactionType::Chargeback,
+                1,
+                1,
+                None,
+            ))
+            .await
+            .unwrap()
+            .account;
+        assert_eq!(account.currency, "EUR");
+        assert_eq!(account.available, Decimal::from(2));
+        assert_eq!(account.held, Decimal::from(0));
+        assert!(account.locked);
+        assert_eq!(
+            ledger.account(2, "EUR").await.unwrap().available,
+            Decimal::from(1)
+        );
+        assert_eq!(ledger.accounts().await.len(), 3);
+        assert_eq!(
+            ledger.revenue().await,
+            FeesMap::from([(String::new(), Decimal::from(1))])
+        );
+        assert_eq!(
+            ledger.history(2).await.unwrap(),
+            vec![AdminAction {
+                tx: 5,
+                tx_type: TransactionType::Freeze,
+                reason: "kyc".to_string(),
+            }]
+        );
+
+        // So do journals, which still match the balances
+        let trial = ledger.trial_balance().await.unwrap();
+        assert_eq!(
+            trial.totals("EUR", Account::ChargebackLoss).balance(),
+            Decimal::from(3)
+        );
+        drop(ledger);
+
+        // Databases from before journals get opening ones, from the opening account
+        let conn = Connection::open(path).unwrap();
+        conn.execute_batch("DROP TABLE journal; PRAGMA user_version = 4;")
+            .unwrap();
+        drop(conn);
+        let ledger = Ledger::from_storage(Arc::new(SqliteStorage::open(path).unwrap()));
+        let trial = ledger.trial_balance().await.unwrap();
+        assert_eq!(
+            trial.totals("EUR", Account::Opening).balance(),
+            Decimal::from(-3)
+        );
+        assert_eq!(
+            trial.totals("EUR", Account::ChargebackLoss),
+            Totals::default()
+        );
+
+        let conn = Connection::open(path).unwrap();
+        let opening: u32 = conn
+            .query_row("SELECT COUNT(*) FROM journal WHERE tx IS NULL", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(opening, 3);
+        drop(conn);
+
+        drop(ledger);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_migrations() {
+        let path = std::env::temp_dir().join(format!("pay-test-v1-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        // Created by a version without migrations, with the first schema
+        let conn = Connection::open(path).unwrap();
+        conn.execute_batch(
+            "
+            CREATE TABLE accounts (client INTEGER PRIMARY KEY, available TEXT NOT NULL,
+                held TEXT NOT NULL, locked INTEGER NOT NULL, insertion_order INTEGER NOT NULL);
+            CREATE TABLE transactions (tx INTEGER PRIMARY KEY, client INTEGER NOT NULL,
+                type TEXT NOT NULL, amount TEXT);
+            CREATE TABLE disputes (tx INTEGER PRIMARY KEY, client INTEGER NOT NULL,
+                disputed TEXT NOT NULL, charged_back TEXT NOT NULL);
+            CREATE TABLE seen_transactions (tx INTEGER NOT NULL, client INTEGER NOT NULL,
+                PRIMARY KEY (client, tx));
+            CREATE TABLE claimed_transactions (tx INTEGER PRIMARY KEY, client INTEGER NOT NULL);
+
+            INSERT INTO accounts VALUES (1, '10', '2', 0, 0);
+            INSERT INTO transactions VALUES (1, 1, 'deposit', '12');
+            INSERT INTO disputes VALUES (1, 1, '2', '0');
+            INSERT INTO seen_transactions VALUES (1, 1);
+            INSERT INTO claimed_transactions VALUES (1, 1);
+            ",
+        )
+        .unwrap();
+        drop(conn);
+
+        let ledger = Ledger::from_storage(Arc::new(SqliteStorage::open(path).unwrap()));
+        let account = ledger.account(1, NO_CURRENCY).await.unwrap();
+        assert_eq!(
+            (account.available, account.held),
+            (Decimal::from(10), Decimal::from(2))
+        );
+        ledger.trial_balance().await.unwrap();
+
+        let account = ledger
+            .apply(ParsedTransaction::new(TransactionType::Resolve, 1, 1, None))
+            .await
+            .unwrap()
+            .account;
+        assert_eq!(account.available, Decimal::from(12));
+        ledger
+            .apply(ParsedTransaction {
+                currency: Some("EUR".to_string()),
+                ..ParsedTransaction::new(TransactionType::Deposit, 1, 2, Some(1.into()))
+            })
+            .await
+            .unwrap();
+        ledger
+            .apply(ParsedTransaction::new(
+                TransactionType::Fee,
+                1,
+                3,
+                Some(1.into()),
+            ))
+            .await
+            .unwrap();
+        drop(ledger);
+
+        let conn = Connection::open(path).unwrap();
+        let version: usize = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        drop(conn);
+
+        // And opens as it was left
+        let ledger = Ledger::from_storage(Arc::new(SqliteStorage::open(path).unwrap()));
+        assert_eq!(
+            ledger.account(1, NO_CURRENCY).await.unwrap().available,
+            Decimal::from(11)
+        );
+        assert_eq!(
+            ledger.account(1, "EUR").await.unwrap().available,
+            Decimal::from(1)
+        );
+        ledger.trial_balance().await.unwrap();
+
+        drop(ledger);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_transfers_migration() {
+        let path = std::env::temp_dir().join(format!("pay-test-v5-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let ledger = Ledger::from_storage(Arc::new(SqliteStorage::open(path).unwrap()));
+        for tx in [
+            ParsedTransaction::new(TransactionType::Deposit, 2, 1, Some(5.into())),
+            ParsedTransaction::new(TransactionType::Deposit, 1, 2, Some(10.into())),
+            ParsedTransaction {
+                to: Some(2),
+                ..ParsedTransaction::new(TransactionType::Transfer, 1, 3, Some(4.into()))
+            },
+        ] {
+            ledger.apply(tx).await.unwrap();
+        }
+        drop(ledger);
+
+        // Written by version 5, which only kept transfers in the journal of the sender
+        let conn = Connection::open(path).unwrap();
+        conn.execute_batch(
+            "
+            DELETE FROM journal WHERE client = 2 AND tx = 3;
+            PRAGMA user_version = 5;
+            ",
+        )
+        .unwrap();
+        drop(conn);
+
+        let ledger = Ledger::from_storage(Arc::new(SqliteStorage::open(path).unwrap()));
+        let trial = ledger.trial_balance().await.unwrap();
+        assert_eq!(
+            trial.totals(NO_CURRENCY, Account::Available(2)).balance(),
+            Decimal::from(9)
+        );
+        drop(ledger);
+
+        let conn = Connection::open(path).unwrap();
+        let received: (u32, u32) = conn
+            .query_row(
+                "SELECT seq, tx FROM journal WHERE client = 2 ORDER BY seq DESC",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(received, (1, 3));
+
+        // A balance that doesn't match the journal of its client fails the trial balance
+        conn.execute("UPDATE balances SET available = '10' WHERE client = 2", [])
+            .unwrap();
+        drop(conn);
+        let ledger = Ledger::from_storage(Arc::new(SqliteStorage::open(path).unwrap()));
+        assert_eq!(
+            ledger.trial_balance().await.unwrap_err(),
+            Error::UnbalancedJournal
+        );
+        drop(ledger);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_failed_write() {
+        let path = std::env::temp_dir().join(format!("pay-test-fail-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let ledger = Ledger::from_storage(Arc::new(SqliteStorage::open(path).unwrap()));
+        ledger
+            .apply(ParsedTransaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(10.into()),
+            ))
+            .await
+            .unwrap();
+
+        let conn = Connection::open(path).unwrap();
+        conn.execute("ALTER TABLE seen_transactions RENAME TO broken", [])
+            .unwrap();
+
+        // Nothing changes in memory when the database can't be written, and the id isn't used up
+        assert_eq!(
+            ledger
+                .apply(ParsedTransaction::new(
+                    TransactionType::Deposit,
+                    1,
+                    2,
+                    Some(5.into())
+                ))
+                .await,
+            Err(Error::FailedStoring)
+        );
+        assert_eq!(
+            ledger
+                .apply(ParsedTransaction::new(
+                    TransactionType::Deposit,
+                    2,
+                    3,
+                    Some(5.into())
+                ))
+                .await,
+            Err(Error::FailedStoring)
+        );
+        assert_eq!(ledger.accounts().await.len(), 1);
+        assert_eq!(
+            ledger.account(1, "").await.unwrap().available,
+            Decimal::from(10)
+        );
+
+        conn.execute("ALTER TABLE broken RENAME TO seen_transactions", [])
+            .unwrap();
+        drop(conn);
+        ledger
+            .apply(ParsedTransaction::new(
+                TransactionType::Deposit,
+                2,
+                2,
+                Some(5.into()),
+            ))
+            .await
+            .unwrap();
+
+        drop(ledger);
+        let ledger = Ledger::from_storage(Arc::new(SqliteStorage::open(path).unwrap()));
+        assert_eq!(ledger.accounts().await.len(), 2);
+        assert_eq!(
+            ledger
+                .apply(ParsedTransaction::new(
+                    TransactionType::Deposit,
+                    1,
+                    2,
+                    Some(5.into())
+                ))
+                .await,
+            Err(Error::DuplicateTransaction)
+        );
+
+        drop(ledger);
+        std::fs::remove_file(path).unwrap();
+    }
+}
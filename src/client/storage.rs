@@ -0,0 +1,391 @@
+use async_trait::async_trait;
+use std::{collections::hash_map::Entry, future::Future, sync::Arc};
+use tokio::sync::{Mutex, RwLock};
+
+use crate::{
+    client::{
+        db::{generate_client_db, generate_transactions_index, ClientsDB, TransactionsIndex},
+        policy::Policy,
+        Client, ClientID, SerializableClient,
+    },
+    error::Error,
+    transaction::{Transaction, TransactionID, NO_CURRENCY},
+};
+
+/// Where clients, their transactions and the owner of every transaction ID are kept.
+///
+/// `ClientsManager` decides what to apply; a `Storage` only keeps the result.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Client that claimed `tx_id` with a deposit/withdrawal, if any.
+    async fn tx_owner(&self, tx_id: TransactionID) -> Option<ClientID>;
+
+    /// Claims `tx_id` for `client_id`, unless a client already did.
+    /// Returns the client that already owned `tx_id`, if any.
+    async fn claim_tx(
+        &self,
+        tx_id: TransactionID,
+        client_id: ClientID,
+    ) -> Result<Option<ClientID>, Error>;
+
+    /// Gives up the claim of `client_id` on `tx_id`, once its transaction was rejected.
+    async fn release_tx(&self, tx_id: TransactionID, client_id: ClientID) -> Result<(), Error>;
+
+    /// Applies `tx` to the client, creating it on its first transaction.
+    /// Returns the state of the client right after it (see `SerializableClient::after`).
+    async fn add_transaction(
+        &self,
+        client_id: ClientID,
+        tx_id: TransactionID,
+        tx: Transaction,
+        policy: &Policy,
+    ) -> Result<SerializableClient, Error>;
+
+    /// Applies the transfer `tx` from `from` to `to`, which must already exist.
+    /// Either both clients are changed (and stored), or neither is.
+    /// Returns the state of both right after it, sender first.
+    async fn transfer(
+        &self,
+        from: ClientID,
+        to: ClientID,
+        tx_id: TransactionID,
+        tx: Transaction,
+        policy: &Policy,
+    ) -> Result<(SerializableClient, SerializableClient), Error>;
+
+    /// Calls `f` with the client, if it exists. Returns whether it does.
+    async fn visit_client(
+        &self,
+        client_id: ClientID,
+        f: &mut (dyn for<'c> FnMut(&'c Client) + Send),
+    ) -> bool;
+
+    /// Calls `f` with every client, in no particular order.
+    // (the explicit lifetime keeps async_trait from tying the borrow of each client to `f`)
+    async fn visit_clients(&self, f: &mut (dyn for<'c> FnMut(&'c Client) + Send));
+
+    /// Owner of every transaction ID, in no particular order.
+    async fn tx_index(&self) -> Vec<(TransactionID, ClientID)>;
+}
+
+/// Reads the client with `f`, if it exists.
+pub async fn read_client<F, R>(storage: &dyn Storage, client_id: ClientID, f: F) -> Option<R>
+where
+    F: FnOnce(&Client) -> R + Send,
+    R: Send,
+{
+    let mut f = Some(f);
+    let mut result = None;
+    storage
+        .visit_client(client_id, &mut |client| {
+            result = f.take().map(|f| f(client));
+        })
+        .await;
+
+    result
+}
+
+/// Keeps everything in memory, in a `ClientsDB` and a `TransactionsIndex`.
+pub struct MemoryStorage {
+    map: ClientsDB,
+    tx_index: TransactionsIndex,
+    // Held while creating a client in `update_committed`
+    creating: Mutex<()>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::from_parts(generate_client_db(), generate_transactions_index())
+    }
+
+    pub fn from_parts(map: ClientsDB, tx_index: TransactionsIndex) -> MemoryStorage {
+        MemoryStorage {
+            map,
+            tx_index,
+            creating: Mutex::new(()),
+        }
+    }
+
+    /// Calls `f` with the client, creating it if it doesn't exist yet.
+    pub async fn update<F, R>(&self, client_id: ClientID, f: F) -> R
+    where
+        F: FnOnce(&mut Client) -> R,
+    {
+        // If there is a client in the DB, update it
+        {
+            let db_read = self.map.read().await;
+
+            if let Some(client) = db_read.get(&client_id) {
+                return f(&mut *client.write().await);
+            }
+        }
+
+        let mut db_write = self.map.write().await;
+
+        // Clients are never removed, so the current size is the next position
+        let insertion_order = db_write.len();
+
+        // Check again, in case it got created right before we acquired the write_lock
+        match db_write.entry(client_id) {
+            Entry::Occupied(entry) => f(&mut *entry.get().write().await),
+            Entry::Vacant(entry) => {
+                let mut client = Client::new(client_id);
+                client.insertion_order = insertion_order;
+                let result = f(&mut client);
+
+                entry.insert(Arc::new(RwLock::new(client)));
+                result
+            }
+        }
+    }
+
+    /// Calls `f` with the client (a new one if it doesn't exist yet), then waits for `commit`
+    /// with the client as `f` left it and what `f` returned (eg. to store what changed). If `commit` fails, what `f`
+    /// did is undone (see `Client::checkpoint`), and a new client isn't kept.
+    /// Only this client stays locked until `commit` is done.
+    pub async fn update_committed<F, C, Fut, R>(
+        &self,
+        client_id: ClientID,
+        tx_id: TransactionID,
+        f: F,
+        commit: C,
+    ) -> Result<R, Error>
+    where
+        F: FnOnce(&mut Client) -> R,
+        C: FnOnce(&Client, &R) -> Fut,
+        Fut: Future<Output = Result<(), Error>>,
+    {
+        let mut creating = None;
+        let client = loop {
+            if let Some(client) = self.client(client_id).await {
+                break Some(client);
+            }
+            if creating.is_some() {
+                break None;
+            }
+            // Clients are created one at a time, so checking again once it's our turn
+            //    tells whether it got created in between
+            creating = Some(self.creating.lock().await);
+        };
+
+        match client {
+            Some(client) => {
+                let mut client = client.write().await;
+                let checkpoint = client.checkpoint(tx_id);
+                let result = f(&mut client);
+
+                if let Err(e) = commit(&client, &result).await {
+                    client.undo(checkpoint);
+                    return Err(e);
+                }
+                Ok(result)
+            }
+            // Only added once committed: until then, it doesn't exist
+            None => {
+                let mut client = Client::new(client_id);
+                client.insertion_order = self.map.read().await.len();
+                let result = f(&mut client);
+                commit(&client, &result).await?;
+
+                self.map
+                    .write()
+                    .await
+                    .insert(client_id, Arc::new(RwLock::new(client)));
+                Ok(result)
+            }
+        }
+    }
+
+    /// Same as `update_committed`, for two clients that must already exist
+    /// (see `update_pair`).
+    pub async fn update_pair_committed<F, C, Fut, R>(
+        &self,
+        from: ClientID,
+        to: ClientID,
+        tx_id: TransactionID,
+        f: F,
+        commit: C,
+    ) -> Result<R, Error>
+    where
+        F: FnOnce(&mut Client, &mut Client) -> R,
+        C: FnOnce(&Client, &Client, &R) -> Fut,
+        Fut: Future<Output = Result<(), Error>>,
+    {
+        if from == to {
+            return Err(Error::InvalidTransfer);
+        }
+
+        let from_client = self.client(from).await.ok_or(Error::ClientNotFound)?;
+        let to_client = self.client(to).await.ok_or(Error::RecipientNotFound)?;
+
+        let (mut from_client, mut to_client) = if from < to {
+            let from_client = from_client.write().await;
+            (from_client, to_client.write().await)
+        } else {
+            let to_client = to_client.write().await;
+            (from_client.write().await, to_client)
+        };
+
+        let checkpoints = (from_client.checkpoint(tx_id), to_client.checkpoint(tx_id));
+        let result = f(&mut from_client, &mut to_client);
+
+        if let Err(e) = commit(&from_client, &to_client, &result).await {
+            from_client.undo(checkpoints.0);
+            to_client.undo(checkpoints.1);
+            return Err(e);
+        }
+        Ok(result)
+    }
+
+    // The map is only locked while getting the client, not while it's updated
+    async fn client(&self, client_id: ClientID) -> Option<Arc<RwLock<Client>>> {
+        self.map.read().await.get(&client_id).map(Arc::clone)
+    }
+
+    /// Calls `f` with both clients, if they exist, holding both of their locks.
+    /// `from` and `to` must differ.
+    pub async fn update_pair<F, R>(&self, from: ClientID, to: ClientID, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut Client, &mut Client) -> R,
+    {
+        if from == to {
+            return Err(Error::InvalidTransfer);
+        }
+
+        let db_read = self.map.read().await;
+        let from_client = db_read.get(&from).ok_or(Error::ClientNotFound)?;
+        let to_client = db_read.get(&to).ok_or(Error::RecipientNotFound)?;
+
+        // Always locked by client id, so opposite transfers can't wait on each other
+        if from < to {
+            let mut from_client = from_client.write().await;
+            let mut to_client = to_client.write().await;
+            Ok(f(&mut from_client, &mut to_client))
+        } else {
+            let mut to_client = to_client.write().await;
+            let mut from_client = from_client.write().await;
+            Ok(f(&mut from_client, &mut to_client))
+        }
+    }
+}
+
+/// State of both clients of a transfer, in the currency it moved.
+pub fn transferred(
+    from: &Client,
+    to: &Client,
+    currency: Option<&str>,
+) -> (SerializableClient, SerializableClient) {
+    let currency = currency.unwrap_or(NO_CURRENCY);
+    (
+        SerializableClient::new(from, currency),
+        SerializableClient::new(to, currency),
+    )
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        MemoryStorage::new()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn tx_owner(&self, tx_id: TransactionID) -> Option<ClientID> {
+        self.tx_index.read().await.get(&tx_id).copied()
+    }
+
+    async fn claim_tx(
+        &self,
+        tx_id: TransactionID,
+        client_id: ClientID,
+    ) -> Result<Option<ClientID>, Error> {
+        match self.tx_index.write().await.entry(tx_id) {
+            Entry::Occupied(entry) => Ok(Some(*entry.get())),
+            Entry::Vacant(entry) => {
+                entry.insert(client_id);
+                Ok(None)
+            }
+        }
+    }
+
+    async fn release_tx(&self, tx_id: TransactionID, client_id: ClientID) -> Result<(), Error> {
+        let mut tx_index = self.tx_index.write().await;
+        if tx_index.get(&tx_id) == Some(&client_id) {
+            tx_index.remove(&tx_id);
+        }
+
+        Ok(())
+    }
+
+    async fn add_transaction(
+        &self,
+        client_id: ClientID,
+        tx_id: TransactionID,
+        tx: Transaction,
+        policy: &Policy,
+    ) -> Result<SerializableClient, Error> {
+        let (tx_type, currency) = (tx.tx_type.clone(), tx.currency.clone());
+
+        self.update(client_id, |client| {
+            client.add_transaction_with(tx_id, tx, policy)?;
+            Ok(SerializableClient::after(
+                client,
+                tx_id,
+                &tx_type,
+                currency.as_deref(),
+            ))
+        })
+        .await
+    }
+
+    async fn transfer(
+        &self,
+        from: ClientID,
+        to: ClientID,
+        tx_id: TransactionID,
+        tx: Transaction,
+        policy: &Policy,
+    ) -> Result<(SerializableClient, SerializableClient), Error> {
+        let currency = tx.currency.clone();
+
+        self.update_pair(from, to, |from, to| {
+            from.transfer(to, tx_id, tx, policy)?;
+            Ok(transferred(from, to, currency.as_deref()))
+        })
+        .await?
+    }
+
+    async fn visit_client(
+        &self,
+        client_id: ClientID,
+        f: &mut (dyn for<'c> FnMut(&'c Client) + Send),
+    ) -> bool {
+        let db_read = self.map.read().await;
+
+        match db_read.get(&client_id) {
+            Some(client) => {
+                f(&*client.read().await);
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn visit_clients(&self, f: &mut (dyn for<'c> FnMut(&'c Client) + Send)) {
+        let db_read = self.map.read().await;
+
+        for client in db_read.values() {
+            let client = client.read().await;
+            f(&client);
+        }
+    }
+
+    async fn tx_index(&self) -> Vec<(TransactionID, ClientID)> {
+        self.tx_index
+            .read()
+            .await
+            .iter()
+            .map(|(tx_id, client_id)| (*tx_id, *client_id))
+            .collect()
+    }
+}
@@ -1,28 +1,75 @@
 use std::fmt;
 
-#[derive(Debug, PartialEq)]
+use crate::source::RecordLocation;
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Error {
     InvalidAmount,
+    InvalidRecord,
+    RecordTooLong,
+    // A record that can't be read, and where it is (only in strict mode)
+    MalformedRecord(Box<RecordLocation>),
+    // Input that couldn't be read any further, and where it stopped
+    FailedReading(Box<RecordLocation>),
 
     TransactionNotFound,
     DuplicateTransaction,
-
-    TransactionAccessError,
+    TransactionClientMismatch,
 
     NoAvailableFunds,
     DuplicateDispute,
+    DisputeAmountExceeded,
     DisputeNotFound,
     MissingHeldFunds,
 
     NotEnoughChargeback,
 
+    AccountLocked,
+    AccountNotLocked,
+    ClientNotFound,
+    MissingReason,
+
+    MissingTimestamp,
+    UnknownRate,
+    InvalidConversion,
+
+    InvalidTransfer,
+    RecipientNotFound,
+    RecipientLocked,
+
+    UnbalancedJournal,
+
     UnknownFile,
+    UnknownFormat,
+    FailedWriting,
+
+    InvalidSnapshot,
+    UnsupportedSnapshotVersion,
+
+    InvalidLog,
+    FailedWritingLog,
+
+    InvalidDatabase,
+    FailedStoring,
+
+    FailedServing,
 
     FailedPushingTx,
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "(Error: {:#?})", self)
+        match self {
+            Error::MalformedRecord(location) => {
+                write!(f, "(Error: MalformedRecord at {})", location)
+            }
+            Error::FailedReading(location) => {
+                write!(f, "(Error: FailedReading at {})", location)
+            }
+            _ => write!(f, "(Error: {:#?})", self),
+        }
     }
 }
+
+impl std::error::Error for Error {}
@@ -0,0 +1,150 @@
+use std::{collections::HashMap, fs::File, io::Read};
+
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::{error::Error, transaction::TransactionType};
+
+#[derive(Deserialize, Debug)]
+struct FeeRecord {
+    #[serde(rename = "type")]
+    tx_type: String,
+    flat: Option<Decimal>,
+    percentage: Option<Decimal>,
+    min: Option<Decimal>,
+    max: Option<Decimal>,
+}
+
+/// Fee charged on one transaction type: a flat part plus a percentage of the amount,
+/// kept between `min` and `max`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Fee {
+    pub flat: Decimal,
+    /// In percent, eg. `1.5` for 1.5%
+    pub percentage: Decimal,
+    pub min: Option<Decimal>,
+    pub max: Option<Decimal>,
+}
+
+impl Fee {
+    pub fn flat(flat: Decimal) -> Fee {
+        Fee {
+            flat,
+            ..Fee::default()
+        }
+    }
+
+    /// Fee on `amount`, to 4 decimal places (without trailing zeros, eg. `1` rather than `1.00`).
+    pub fn on(&self, amount: Decimal) -> Decimal {
+        let fee = self.flat + amount * self.percentage / Decimal::ONE_HUNDRED;
+        let fee = self.min.map_or(fee, |min| fee.max(min));
+        let fee = self.max.map_or(fee, |max| fee.min(max));
+
+        fee.round_dp(4).normalize()
+    }
+}
+
+/// Fees clients are charged on withdrawals and chargebacks, by transaction type.
+///
+/// Types without a fee (which is all of them by default) are free.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeeSchedule {
+    fees: HashMap<TransactionType, Fee>,
+}
+
+impl FeeSchedule {
+    pub fn new() -> FeeSchedule {
+        FeeSchedule::default()
+    }
+
+    /// Reads a csv with a `type,flat,percentage,min,max` header, where any of the
+    /// amounts may be left empty, eg. `withdrawal,0.5,1,,20`.
+    pub fn from_reader<R: Read>(reader: R) -> Result<FeeSchedule, Error> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+
+        reader
+            .deserialize()
+            .try_fold(FeeSchedule::new(), |schedule, record| {
+                let record: FeeRecord = record.map_err(|_| Error::InvalidRecord)?;
+                let tx_type =
+                    TransactionType::from_name(&record.tx_type).ok_or(Error::InvalidRecord)?;
+                let fee = Fee {
+                    flat: record.flat.unwrap_or_default(),
+                    percentage: record.percentage.unwrap_or_default(),
+                    min: record.min,
+                    max: record.max,
+                };
+
+                let amounts = [Some(fee.flat), Some(fee.percentage), fee.min, fee.max];
+                if amounts
+                    .iter()
+                    .flatten()
+                    .any(|amount| *amount < Decimal::ZERO)
+                    || matches!((fee.min, fee.max), (Some(min), Some(max)) if min > max)
+                {
+                    Err(Error::InvalidRecord)
+                } else {
+                    Ok(schedule.with_fee(tx_type, fee))
+                }
+            })
+    }
+
+    pub fn from_path(path: &str) -> Result<FeeSchedule, Error> {
+        let file = File::open(path).map_err(|_| Error::UnknownFile)?;
+        FeeSchedule::from_reader(file)
+    }
+
+    /// Charges `fee` on every transaction of `tx_type`.
+    pub fn with_fee(mut self, tx_type: TransactionType, fee: Fee) -> FeeSchedule {
+        self.fees.insert(tx_type, fee);
+        self
+    }
+
+    /// Fee on a transaction of `tx_type` moving `amount`.
+    pub fn fee(&self, tx_type: &TransactionType, amount: Decimal) -> Decimal {
+        self.fees
+            .get(tx_type)
+            .map_or(Decimal::ZERO, |fee| fee.on(amount))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn test_fee_schedule() {
+        let fees = "type,flat,percentage,min,max
+withdrawal,0.5,1,1,3
+chargeback,15,,,
+";
+        let schedule = FeeSchedule::from_reader(fees.as_bytes()).unwrap();
+        let fee = |tx_type, amount: &str| {
+            schedule
+                .fee(&tx_type, Decimal::from_str(amount).unwrap())
+                .to_string()
+        };
+
+        // 0.5 + 1%, between 1 and 3
+        assert_eq!(fee(TransactionType::Withdrawal, "10"), "1");
+        assert_eq!(fee(TransactionType::Withdrawal, "100"), "1.5");
+        assert_eq!(fee(TransactionType::Withdrawal, "123.4567"), "1.7346");
+        assert_eq!(fee(TransactionType::Withdrawal, "1000"), "3");
+        assert_eq!(fee(TransactionType::Chargeback, "1000"), "15");
+        assert_eq!(fee(TransactionType::Deposit, "1000"), "0");
+
+        for invalid in [
+            "type,flat,percentage,min,max\nrefund,1,,,\n",
+            "type,flat,percentage,min,max\nwithdrawal,-1,,,\n",
+            "type,flat,percentage,min,max\nwithdrawal,,1,5,2\n",
+        ] {
+            assert_eq!(
+                FeeSchedule::from_reader(invalid.as_bytes()),
+                Err(Error::InvalidRecord)
+            );
+        }
+    }
+}
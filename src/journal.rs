@@ -0,0 +1,278 @@
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::{storage::Storage, ClientID},
+    error::Error,
+    transaction::{Currency, TransactionID},
+};
+
+/// Account funds are kept in, or come from.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Account {
+    /// Funds of a client it can use
+    Available(ClientID),
+    /// Funds of a client held by disputes
+    Held(ClientID),
+    /// Outside of the ledger: where deposits come from and withdrawals go to
+    Funding,
+    /// Where the balances clients had before journals existed come from. Entries from it
+    /// weren't posted by transactions, so the trial balance can't check them.
+    Opening,
+    /// Where charged back deposits go
+    ChargebackLoss,
+    /// Fees collected by the house
+    Revenue,
+    /// Counterpart of conversions, in both currencies
+    Exchange,
+}
+
+/// Written as `available:<client>`, `held:<client>`, `funding`, `opening`,
+/// `chargeback_loss`, `revenue` or `exchange`, eg. in sqlite.
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Account::Available(client) => write!(f, "available:{}", client),
+            Account::Held(client) => write!(f, "held:{}", client),
+            Account::Funding => write!(f, "funding"),
+            Account::Opening => write!(f, "opening"),
+            Account::ChargebackLoss => write!(f, "chargeback_loss"),
+            Account::Revenue => write!(f, "revenue"),
+            Account::Exchange => write!(f, "exchange"),
+        }
+    }
+}
+
+impl FromStr for Account {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let client = |client: &str| client.parse().map_err(|_| Error::UnknownFormat);
+        match s.split_once(':') {
+            Some(("available", id)) => Ok(Account::Available(client(id)?)),
+            Some(("held", id)) => Ok(Account::Held(client(id)?)),
+            Some(_) => Err(Error::UnknownFormat),
+            None => match s {
+                "funding" => Ok(Account::Funding),
+                "opening" => Ok(Account::Opening),
+                "chargeback_loss" => Ok(Account::ChargebackLoss),
+                "revenue" => Ok(Account::Revenue),
+                "exchange" => Ok(Account::Exchange),
+                _ => Err(Error::UnknownFormat),
+            },
+        }
+    }
+}
+
+/// Moves `amount` from the `credit` account to the `debit` one, so every entry is
+/// balanced: it debits exactly what it credits.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JournalEntry {
+    /// Transaction that posted the entry, if any (opening balances don't have one)
+    pub tx: Option<TransactionID>,
+    pub currency: Currency,
+    pub debit: Account,
+    pub credit: Account,
+    pub amount: Decimal,
+}
+
+/// Debits and credits of an account.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Totals {
+    pub debits: Decimal,
+    pub credits: Decimal,
+}
+
+impl Totals {
+    /// What's in the account: its debits minus its credits.
+    pub fn balance(&self) -> Decimal {
+        self.debits - self.credits
+    }
+}
+
+/// Debits and credits of every account, per currency, summed over journal entries.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrialBalance {
+    accounts: BTreeMap<(Currency, Account), Totals>,
+}
+
+impl TrialBalance {
+    pub fn new() -> TrialBalance {
+        TrialBalance::default()
+    }
+
+    pub fn add(&mut self, entry: &JournalEntry) {
+        self.totals_mut(&entry.currency, entry.debit).debits += entry.amount;
+        self.totals_mut(&entry.currency, entry.credit).credits += entry.amount;
+    }
+
+    /// Takes back what `add` added for `entry`. Accounts left without entries are dropped.
+    pub(crate) fn remove(&mut self, entry: &JournalEntry) {
+        for (account, debits, credits) in [
+            (entry.debit, entry.amount, Decimal::ZERO),
+            (entry.credit, Decimal::ZERO, entry.amount),
+        ] {
+            let key = (entry.currency.clone(), account);
+            if let Some(totals) = self.accounts.get_mut(&key) {
+                totals.debits -= debits;
+                totals.credits -= credits;
+                if totals.debits.is_zero() && totals.credits.is_zero() {
+                    self.accounts.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Adds the debits and credits of an account, eg. summed by another trial balance.
+    pub fn add_totals(&mut self, currency: &str, account: Account, totals: Totals) {
+        let sum = self.totals_mut(currency, account);
+        sum.debits += totals.debits;
+        sum.credits += totals.credits;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+
+    pub fn totals(&self, currency: &str, account: Account) -> Totals {
+        self.accounts
+            .get(&(currency.to_string(), account))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Every account with entries, by currency and account.
+    pub fn accounts(&self) -> impl Iterator<Item = (&str, Account, Totals)> {
+        self.accounts
+            .iter()
+            .map(|((currency, account), totals)| (currency.as_str(), *account, *totals))
+    }
+
+    /// Fails with `UnbalancedJournal` unless the debits of every currency add up
+    /// to its credits. Every entry is balanced, so they only don't if the totals were
+    /// changed some other way, eg. in a saved state that was edited.
+    pub fn check(&self) -> Result<(), Error> {
+        let mut currencies = BTreeMap::<&str, Totals>::new();
+        for (currency, _, totals) in self.accounts() {
+            let sum = currencies.entry(currency).or_default();
+            sum.debits += totals.debits;
+            sum.credits += totals.credits;
+        }
+
+        match currencies.iter().find(|(_, sum)| sum.debits != sum.credits) {
+            Some((currency, sum)) => {
+                warn!(
+                    "Journal in {:?} doesn't balance: {} debited, {} credited",
+                    currency, sum.debits, sum.credits
+                );
+                Err(Error::UnbalancedJournal)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn totals_mut(&mut self, currency: &str, account: Account) -> &mut Totals {
+        self.accounts
+            .entry((currency.to_string(), account))
+            .or_default()
+    }
+}
+
+/// Trial balance of the journals of all clients, from what each of them adds up to.
+///
+/// Fails with `UnbalancedJournal` if the balances of a client differ from its accounts
+/// in its own journal: a change that didn't post its entry, or balances stored apart
+/// from the journal (eg. in sqlite, where the totals are summed from the stored
+/// entries) that don't match it. Also fails if debits and credits differ (see
+/// `TrialBalance::check`).
+pub async fn trial_balance(storage: &dyn Storage) -> Result<TrialBalance, Error> {
+    let mut trial = TrialBalance::new();
+    let mut unmatched = None;
+    storage
+        .visit_clients(&mut |client| {
+            let totals = client.journal_totals();
+            for (currency, balance) in client.balances() {
+                let available = totals.totals(currency, Account::Available(client.id));
+                let held = totals.totals(currency, Account::Held(client.id));
+                if (available.balance() != balance.available || held.balance() != balance.held)
+                    && unmatched.is_none()
+                {
+                    unmatched = Some((client.id, currency.clone()));
+                }
+            }
+
+            // Transfers are in the journals of both clients: each adds the side of its own
+            //    account, so they're counted once
+            for (currency, account, account_totals) in totals.accounts() {
+                match account {
+                    Account::Available(id) | Account::Held(id) if id != client.id => {}
+                    _ => trial.add_totals(currency, account, account_totals),
+                }
+            }
+        })
+        .await;
+
+    if let Some((client, currency)) = unmatched {
+        warn!(
+            "Balances of client {} in {:?} don't match its journal",
+            client, currency
+        );
+        return Err(Error::UnbalancedJournal);
+    }
+    trial.check()?;
+
+    Ok(trial)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_trial_balance() {
+        let entry = |debit, credit, amount| JournalEntry {
+            tx: Some(1),
+            currency: "EUR".to_string(),
+            debit,
+            credit,
+            amount: Decimal::from(amount),
+        };
+
+        let mut trial = TrialBalance::new();
+        trial.add(&entry(Account::Available(1), Account::Funding, 10));
+        trial.add(&entry(Account::Held(1), Account::Available(1), 4));
+        assert_eq!(trial.check(), Ok(()));
+        assert_eq!(
+            trial.totals("EUR", Account::Available(1)),
+            Totals {
+                debits: Decimal::from(10),
+                credits: Decimal::from(4),
+            }
+        );
+        assert_eq!(
+            trial.totals("EUR", Account::Funding).balance(),
+            Decimal::from(-10)
+        );
+        assert_eq!(trial.totals("USD", Account::Funding), Totals::default());
+
+        // Only possible if the totals are changed some other way than by entries
+        trial.totals_mut("EUR", Account::Revenue).debits += Decimal::from(1);
+        assert_eq!(trial.check(), Err(Error::UnbalancedJournal));
+
+        for account in [
+            Account::Available(3),
+            Account::Held(2),
+            Account::Funding,
+            Account::Opening,
+            Account::ChargebackLoss,
+            Account::Revenue,
+            Account::Exchange,
+        ] {
+            assert_eq!(Account::from_str(&account.to_string()), Ok(account));
+        }
+        assert_eq!(Account::from_str("held:a"), Err(Error::UnknownFormat));
+        assert_eq!(Account::from_str("house"), Err(Error::UnknownFormat));
+    }
+}
@@ -0,0 +1,820 @@
+use std::{collections::HashSet, fs::File, io::BufWriter, sync::Arc};
+use tokio::sync::RwLock;
+
+use crate::{
+    audit::AuditWriter,
+    client::{
+        add_received_transfers,
+        manager::ClientsManager,
+        policy::{DisputePolicy, LockPolicy, Rounding},
+        queue::{generate_clients_queues, CQReceivers, CQSenders, QueuedTransaction},
+        storage::{read_client, MemoryStorage, Storage},
+        Client, ClientID, ClientState, FeesMap, SerializableClient,
+    },
+    error::Error,
+    fees::FeeSchedule,
+    journal::{self, TrialBalance},
+    pipeline::Pipeline,
+    processor::start_processors,
+    rates::RateTable,
+    reader::{start_reader, ReadingStatus},
+    rejections::{Rejection, RejectionsWriter},
+    snapshot::Snapshot,
+    source::{self, TransactionSource},
+    transaction::{AdminAction, ParsedTransaction, TransactionID, TransactionType},
+    wal::{ResumedSource, WriteAheadLog},
+    writer::house_revenue,
+};
+
+/// Result of a transaction that was applied to a client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub tx_id: TransactionID,
+    /// State of the client right after the transaction was applied, in the currency
+    /// it moved funds in.
+    pub account: SerializableClient,
+}
+
+/// In-process entry point to the engine.
+///
+/// Wraps a `Storage` and routes every transaction through `ClientsManager`,
+/// so it follows the exact same rules as the binary.
+pub struct Ledger {
+    storage: Arc<dyn Storage>,
+    manager: ClientsManager,
+    rejections: Option<Arc<RejectionsWriter>>,
+    strict: bool,
+    wal: Option<Arc<WriteAheadLog>>,
+    // Last write-ahead log entry included in the state this ledger started from
+    wal_seq: u64,
+    // Offsets of the input records already applied before a crash
+    applied: HashSet<u64>,
+}
+
+impl Ledger {
+    /// Ledger kept in memory.
+    pub fn new() -> Ledger {
+        Ledger::from_storage(Arc::new(MemoryStorage::new()))
+    }
+
+    pub fn from_storage(storage: Arc<dyn Storage>) -> Ledger {
+        let manager = ClientsManager::new(Arc::clone(&storage));
+
+        Ledger {
+            storage,
+            manager,
+            rejections: None,
+            strict: false,
+            wal: None,
+            wal_seq: 0,
+            applied: HashSet::new(),
+        }
+    }
+
+    /// Stops reading at the first record that can't be parsed (including malformed amounts),
+    /// instead of skipping it.
+    pub fn with_strict(mut self, strict: bool) -> Ledger {
+        self.strict = strict;
+        self
+    }
+
+    /// Records every transaction skipped by `process_file` into `rejections`.
+    pub fn with_rejections(mut self, rejections: RejectionsWriter) -> Ledger {
+        self.rejections = Some(Arc::new(rejections));
+        self
+    }
+
+    /// Records the outcome of every transaction, and the state of its client after it,
+    /// into `audit`.
+    pub fn with_audit(mut self, audit: AuditWriter) -> Ledger {
+        self.manager = self.manager.with_audit(Arc::new(audit));
+        self
+    }
+
+    /// Decides which transactions locked clients still accept. By default, only `unlock`.
+    pub fn with_lock_policy(mut self, policy: LockPolicy) -> Ledger {
+        self.manager = self.manager.with_lock_policy(policy);
+        self
+    }
+
+    /// Decides how much of a deposit disputes hold. By default, only what's still available.
+    pub fn with_dispute_policy(mut self, policy: DisputePolicy) -> Ledger {
+        self.manager = self.manager.with_dispute_policy(policy);
+        self
+    }
+
+    /// Rates `convert` transactions are made at. By default there are none,
+    /// so conversions are rejected.
+    pub fn with_rates(mut self, rates: RateTable) -> Ledger {
+        self.manager = self.manager.with_rates(rates);
+        self
+    }
+
+    /// How converted amounts are rounded. By default, to 4 places, half to even.
+    pub fn with_rounding(mut self, rounding: Rounding) -> Ledger {
+        self.manager = self.manager.with_rounding(rounding);
+        self
+    }
+
+    /// Fees charged on withdrawals and chargebacks, which go to the house. By default, none.
+    pub fn with_fees(mut self, fees: FeeSchedule) -> Ledger {
+        self.manager = self.manager.with_fees(fees);
+        self
+    }
+
+    /// Logs every transaction to the write-ahead log at `path` before applying it.
+    ///
+    /// If the log already has entries (eg. the previous run crashed), they are replayed first,
+    /// and the records they came from are skipped when processing the input again.
+    /// Entries the state was saved with (see `save_state`) aren't replayed again.
+    /// The log is emptied by `finish`, once the run succeeded.
+    pub async fn with_wal(mut self, path: &str) -> Result<Ledger, Error> {
+        let (wal, entries) = WriteAheadLog::open(path)?;
+        if !entries.is_empty() {
+            info!("Recovering {} transactions from {}", entries.len(), path);
+        }
+
+        // Conversions are made at the rate they were logged with: without one, they were
+        //    rejected, and still are whatever the current rates
+        let recovery = self.manager.clone().with_rates(RateTable::new());
+        for entry in entries {
+            if let Some(offset) = entry.offset {
+                self.applied.insert(offset);
+            }
+            // Transactions rejected by their client are logged too, and get rejected again.
+            //    The ones rejected before being logged are only skipped
+            if entry.seq > self.wal_seq && !entry.rejected {
+                if let Err(e) = recovery.replay(entry.tx).await {
+                    info!("Recovered transaction {} rejected: {}", entry.seq, e);
+                }
+            }
+        }
+        wal.continue_after(self.wal_seq).await;
+
+        let wal = Arc::new(wal);
+        self.manager = self.manager.with_wal(Arc::clone(&wal));
+        self.wal = Some(wal);
+        Ok(self)
+    }
+
+    /// Restores a ledger from a snapshot of a previous run.
+    pub fn from_snapshot(snapshot: Snapshot) -> Ledger {
+        let mut clients: Vec<Client> = snapshot.clients.into_iter().map(Client::from).collect();
+        if snapshot.version < 5 {
+            add_received_transfers(&mut clients);
+        }
+        let clients = clients
+            .into_iter()
+            .map(|client| (client.id, Arc::new(RwLock::new(client))))
+            .collect();
+        let tx_index = snapshot.tx_index.into_iter().collect();
+
+        let mut ledger = Ledger::from_storage(Arc::new(MemoryStorage::from_parts(
+            RwLock::new(clients),
+            RwLock::new(tx_index),
+        )));
+        ledger.wal_seq = snapshot.wal_seq;
+        ledger
+    }
+
+    pub fn load_state(path: &str) -> Result<Ledger, Error> {
+        let file = File::open(path).map_err(|_| Error::UnknownFile)?;
+        Ok(Ledger::from_snapshot(Snapshot::read_from(file)?))
+    }
+
+    /// Complete state of all clients, to be restored with `from_snapshot`.
+    pub async fn snapshot(&self) -> Snapshot {
+        let mut clients = vec![];
+        self.storage
+            .visit_clients(&mut |client| clients.push(ClientState::from(client)))
+            .await;
+
+        let wal_seq = match &self.wal {
+            Some(wal) => wal.last_seq().await,
+            None => self.wal_seq,
+        };
+        Snapshot::new(clients, self.storage.tx_index().await).with_wal_seq(wal_seq)
+    }
+
+    /// Saves the state to `path`, along with how far into the write-ahead log (if any) it is.
+    pub async fn save_state(&self, path: &str) -> Result<(), Error> {
+        let file = File::create(path).map_err(|_| Error::FailedWriting)?;
+        let mut out = BufWriter::new(file);
+        self.snapshot().await.write_to(&mut out)?;
+
+        out.into_inner()
+            .map_err(|_| Error::FailedWriting)?
+            .sync_all()
+            .map_err(|_| Error::FailedWriting)
+    }
+
+    /// Empties the write-ahead log (if any), once the run it logged succeeded:
+    /// the next run doesn't recover it, nor skip any of its input.
+    pub async fn finish(&self) -> Result<(), Error> {
+        match &self.wal {
+            Some(wal) => wal.truncate().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Whether records that can't be parsed stop processing, instead of being skipped.
+    pub fn strict(&self) -> bool {
+        self.strict
+    }
+
+    pub fn storage(&self) -> Arc<dyn Storage> {
+        Arc::clone(&self.storage)
+    }
+
+    /// Applies a single transaction, returning the reason if it was rejected.
+    pub async fn apply(&self, tx: ParsedTransaction) -> Result<Outcome, Error> {
+        let tx_id = tx.tx_id;
+        let account = self.manager.push_tx(tx).await?;
+
+        Ok(Outcome { tx_id, account })
+    }
+
+    /// Locks the client, recording `reason`. `tx_id` is unique like any other transaction ID.
+    pub async fn freeze(
+        &self,
+        client_id: ClientID,
+        tx_id: TransactionID,
+        reason: &str,
+    ) -> Result<Outcome, Error> {
+        self.apply(admin_tx(TransactionType::Freeze, client_id, tx_id, reason))
+            .await
+    }
+
+    /// Unlocks the client (eg. after a chargeback), recording `reason`.
+    pub async fn unlock(
+        &self,
+        client_id: ClientID,
+        tx_id: TransactionID,
+        reason: &str,
+    ) -> Result<Outcome, Error> {
+        self.apply(admin_tx(TransactionType::Unlock, client_id, tx_id, reason))
+            .await
+    }
+
+    /// State of the client in `currency` (`NO_CURRENCY` for transactions without one).
+    pub async fn account(&self, client_id: ClientID, currency: &str) -> Option<SerializableClient> {
+        read_client(&*self.storage, client_id, |client| {
+            SerializableClient::new(client, currency)
+        })
+        .await
+    }
+
+    /// State of the client of `tx`, in the currency `tx` moves funds in.
+    pub async fn account_for(&self, tx: &ParsedTransaction) -> Option<SerializableClient> {
+        self.manager.account_for(tx).await
+    }
+
+    /// State of the client in every currency it has, by currency.
+    pub async fn balances(&self, client_id: ClientID) -> Option<Vec<SerializableClient>> {
+        read_client(&*self.storage, client_id, SerializableClient::rows).await
+    }
+
+    /// Freezes and unlocks applied to the client, and chargebacks that locked it, oldest first.
+    pub async fn history(&self, client_id: ClientID) -> Option<Vec<AdminAction>> {
+        read_client(&*self.storage, client_id, |client| {
+            client.history().to_vec()
+        })
+        .await
+    }
+
+    /// State of all clients in every currency, ordered by `ClientID` and currency.
+    pub async fn accounts(&self) -> Vec<SerializableClient> {
+        let mut accounts = vec![];
+        self.storage
+            .visit_clients(&mut |client| accounts.extend(SerializableClient::rows(client)))
+            .await;
+        accounts.sort_unstable_by(|a, b| (a.client, &a.currency).cmp(&(b.client, &b.currency)));
+
+        accounts
+    }
+
+    /// Fees the house collected from all clients, by currency.
+    pub async fn revenue(&self) -> FeesMap {
+        house_revenue(&*self.storage).await
+    }
+
+    /// Trial balance of the journals of all clients. Fails with `UnbalancedJournal` if
+    /// the balances of a client don't match its journal (see `journal::trial_balance`).
+    pub async fn trial_balance(&self) -> Result<TrialBalance, Error> {
+        journal::trial_balance(&*self.storage).await
+    }
+
+    /// Starts `num_workers` processors that apply transactions as they are submitted,
+    /// until the returned `Pipeline` is shut down.
+    pub async fn pipeline(&self, num_workers: u32) -> Pipeline {
+        Pipeline::start(&self.manager, &self.rejections, num_workers).await
+    }
+
+    /// Reads `file_in` and processes it with `num_workers` concurrent processors.
+    /// The input format is guessed from the file extension.
+    pub async fn process_file(&self, file_in: &str, num_workers: u32) -> Result<(), Error> {
+        let source = source::open(file_in, None)?;
+        self.process_source(source, num_workers).await
+    }
+
+    /// Reads all transactions from `source` and processes them with `num_workers`
+    /// concurrent processors.
+    pub async fn process_source(
+        &self,
+        source: Box<dyn TransactionSource + Send>,
+        num_workers: u32,
+    ) -> Result<(), Error> {
+        let source = Box::new(ResumedSource::new(source, self.applied.clone()));
+        let reading_status = Arc::new(RwLock::new(ReadingStatus::new()));
+
+        let (notifier_sender, notifier_receiver): (
+            async_channel::Sender<ClientID>,
+            async_channel::Receiver<ClientID>,
+        ) = async_channel::unbounded();
+
+        let (senders, receivers): (CQSenders, CQReceivers) = generate_clients_queues();
+        let pile_senders = Arc::new(senders);
+        let pile_receivers = Arc::new(receivers);
+
+        let processors = start_processors(
+            num_workers,
+            &reading_status,
+            &notifier_receiver,
+            &self.manager,
+            &pile_receivers,
+            &self.rejections,
+            &None,
+        )
+        .await;
+
+        let result = start_reader(
+            source,
+            &reading_status,
+            &notifier_sender,
+            &pile_senders,
+            &pile_receivers,
+            &self.rejections,
+            self.strict,
+        )
+        .await;
+
+        notifier_sender.close();
+        futures::future::join_all(processors).await;
+
+        if let Some(rejections) = &self.rejections {
+            rejections.flush().await;
+        }
+        self.manager.flush().await;
+
+        result
+    }
+
+    /// Applies all transactions from `source` one at a time, in input order.
+    /// Slower than `process_source`, but the outcome doesn't depend on scheduling.
+    pub async fn replay_source(
+        &self,
+        source: Box<dyn TransactionSource + Send>,
+    ) -> Result<(), Error> {
+        let mut source = ResumedSource::new(source, self.applied.clone());
+
+        let mut result = Ok(());
+        while let Some(tx) = source.next_transaction() {
+            match &tx {
+                Err(rejection) if self.strict || rejection.fatal => {
+                    result = Err(rejection.clone().into_error());
+                }
+                _ => (),
+            }
+
+            let rejection = match tx {
+                Ok(QueuedTransaction {
+                    row,
+                    source,
+                    offset,
+                    tx,
+                }) => {
+                    let rejected = self.rejections.as_ref().map(|_| tx.clone());
+                    match self.manager.push_record(offset, tx).await {
+                        Ok(_) => None,
+                        Err(e) => {
+                            rejected.map(|tx| Rejection::new(row, &tx, &e).with_source(source))
+                        }
+                    }
+                }
+                Err(rejection) => Some(rejection),
+            };
+
+            if let (Some(rejections), Some(rejection)) = (&self.rejections, rejection) {
+                rejections.record(rejection).await;
+            }
+            if result.is_err() {
+                break;
+            }
+        }
+
+        if let Some(rejections) = &self.rejections {
+            rejections.flush().await;
+        }
+        self.manager.flush().await;
+
+        result
+    }
+}
+
+impl Default for Ledger {
+    fn default() -> Self {
+        Ledger::new()
+    }
+}
+
+fn admin_tx(
+    tx_type: TransactionType,
+    client_id: ClientID,
+    tx_id: TransactionID,
+    reason: &str,
+) -> ParsedTransaction {
+    ParsedTransaction {
+        reason: Some(reason.to_string()),
+        ..ParsedTransaction::new(tx_type, client_id, tx_id, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{source::CsvSource, transaction::NO_CURRENCY};
+    use rust_decimal::Decimal;
+
+    #[tokio::test]
+    async fn test_apply() {
+        let ledger = Ledger::new();
+
+        let outcome = ledger
+            .apply(ParsedTransaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(10.into()),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(outcome.tx_id, 1);
+        assert_eq!(outcome.account.available, Decimal::from(10));
+
+        assert_eq!(
+            ledger
+                .apply(ParsedTransaction::new(
+                    TransactionType::Withdrawal,
+                    1,
+                    2,
+                    Some(11.into())
+                ))
+                .await,
+            Err(Error::NoAvailableFunds)
+        );
+        assert_eq!(
+            ledger
+                .apply(ParsedTransaction::new(
+                    TransactionType::Deposit,
+                    1,
+                    1,
+                    Some(10.into())
+                ))
+                .await,
+            Err(Error::DuplicateTransaction)
+        );
+
+        ledger
+            .apply(ParsedTransaction::new(TransactionType::Dispute, 1, 1, None))
+            .await
+            .unwrap();
+
+        let account = ledger.account(1, NO_CURRENCY).await.unwrap();
+        assert_eq!(account.available, Decimal::from(0));
+        assert_eq!(account.held, Decimal::from(10));
+        assert_eq!(account.total, Decimal::from(10));
+
+        assert!(ledger.account(2, NO_CURRENCY).await.is_none());
+        assert_eq!(ledger.accounts().await, vec![account]);
+    }
+
+    #[tokio::test]
+    async fn test_global_transaction_ids() {
+        let ledger = Ledger::new();
+
+        ledger
+            .apply(ParsedTransaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(10.into()),
+            ))
+            .await
+            .unwrap();
+
+        // Test reusing another client's transaction id
+        assert_eq!(
+            ledger
+                .apply(ParsedTransaction::new(
+                    TransactionType::Deposit,
+                    2,
+                    1,
+                    Some(10.into())
+                ))
+                .await,
+            Err(Error::DuplicateTransaction)
+        );
+        assert_eq!(
+            ledger
+                .apply(ParsedTransaction::new(
+                    TransactionType::Withdrawal,
+                    2,
+                    1,
+                    Some(10.into())
+                ))
+                .await,
+            Err(Error::DuplicateTransaction)
+        );
+
+        // Test disputing another client's transaction
+        assert_eq!(
+            ledger
+                .apply(ParsedTransaction::new(TransactionType::Dispute, 2, 1, None))
+                .await,
+            Err(Error::TransactionClientMismatch)
+        );
+        assert!(ledger.account(2, NO_CURRENCY).await.is_none());
+
+        ledger
+            .apply(ParsedTransaction::new(TransactionType::Dispute, 1, 1, None))
+            .await
+            .unwrap();
+        assert_eq!(
+            ledger
+                .apply(ParsedTransaction::new(
+                    TransactionType::Chargeback,
+                    2,
+                    1,
+                    None
+                ))
+                .await,
+            Err(Error::TransactionClientMismatch)
+        );
+        assert_eq!(
+            ledger.account(1, NO_CURRENCY).await.unwrap().held,
+            Decimal::from(10)
+        );
+
+        // Test reusing the id of a rejected transaction
+        assert_eq!(
+            ledger
+                .apply(ParsedTransaction::new(
+                    TransactionType::Withdrawal,
+                    2,
+                    2,
+                    Some(10.into())
+                ))
+                .await,
+            Err(Error::NoAvailableFunds)
+        );
+        ledger
+            .apply(ParsedTransaction::new(
+                TransactionType::Deposit,
+                3,
+                2,
+                Some(10.into()),
+            ))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unlock_after_chargeback() {
+        let ledger = Ledger::new();
+
+        for tx in [
+            ParsedTransaction::new(TransactionType::Deposit, 1, 1, Some(10.into())),
+            ParsedTransaction::new(TransactionType::Deposit, 1, 2, Some(5.into())),
+            ParsedTransaction::new(TransactionType::Dispute, 1, 2, None),
+            ParsedTransaction::new(TransactionType::Chargeback, 1, 2, None),
+        ] {
+            ledger.apply(tx).await.unwrap();
+        }
+        assert_eq!(
+            ledger.freeze(1, 3, "fraud").await,
+            Err(Error::AccountLocked)
+        );
+
+        // Admin transaction IDs can't be reused either
+        assert_eq!(
+            ledger.unlock(1, 1, "reviewed").await,
+            Err(Error::DuplicateTransaction)
+        );
+        let outcome = ledger.unlock(1, 3, "reviewed").await.unwrap();
+        assert!(!outcome.account.locked);
+        ledger
+            .apply(ParsedTransaction::new(
+                TransactionType::Withdrawal,
+                1,
+                4,
+                Some(10.into()),
+            ))
+            .await
+            .unwrap();
+
+        ledger.freeze(1, 5, "fraud").await.unwrap();
+        let history = ledger.history(1).await.unwrap();
+        assert_eq!(
+            history
+                .iter()
+                .map(|action| (action.tx, action.reason.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(2, ""), (3, "reviewed"), (5, "fraud")]
+        );
+        assert_eq!(history[0].tx_type, TransactionType::Chargeback);
+
+        // Clients must exist to be frozen or unlocked
+        assert_eq!(
+            ledger.freeze(2, 6, "fraud").await,
+            Err(Error::ClientNotFound)
+        );
+        assert_eq!(
+            ledger.unlock(2, 6, "reviewed").await,
+            Err(Error::ClientNotFound)
+        );
+        assert!(ledger.history(2).await.is_none());
+        ledger.unlock(1, 6, "reviewed").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lock_policy() {
+        let policy = LockPolicy::new()
+            .allow(TransactionType::Deposit)
+            .allow(TransactionType::Resolve)
+            .allow(TransactionType::Chargeback);
+        let ledger = Ledger::new().with_lock_policy(policy);
+
+        for tx in [
+            ParsedTransaction::new(TransactionType::Deposit, 1, 1, Some(10.into())),
+            ParsedTransaction::new(TransactionType::Deposit, 1, 2, Some(5.into())),
+            ParsedTransaction::new(TransactionType::Deposit, 1, 3, Some(5.into())),
+            ParsedTransaction::new(TransactionType::Dispute, 1, 2, None),
+            ParsedTransaction::new(TransactionType::Dispute, 1, 3, None),
+            ParsedTransaction::new(TransactionType::Chargeback, 1, 2, None),
+        ] {
+            ledger.apply(tx).await.unwrap();
+        }
+
+        // Existing disputes can still be settled, and deposits still come in
+        ledger
+            .apply(ParsedTransaction::new(TransactionType::Resolve, 1, 3, None))
+            .await
+            .unwrap();
+        let account = ledger
+            .apply(ParsedTransaction::new(
+                TransactionType::Deposit,
+                1,
+                4,
+                Some(1.into()),
+            ))
+            .await
+            .unwrap()
+            .account;
+        assert!(account.locked);
+        assert_eq!(account.available, Decimal::from(16));
+
+        assert_eq!(
+            ledger
+                .apply(ParsedTransaction::new(
+                    TransactionType::Withdrawal,
+                    1,
+                    5,
+                    Some(1.into())
+                ))
+                .await,
+            Err(Error::AccountLocked)
+        );
+        assert_eq!(
+            ledger
+                .apply(ParsedTransaction::new(TransactionType::Dispute, 1, 1, None))
+                .await,
+            Err(Error::AccountLocked)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transfer() {
+        let ledger = &Ledger::new();
+        let transfer = |from, to, tx_id, amount: u32| ParsedTransaction {
+            to,
+            ..ParsedTransaction::new(TransactionType::Transfer, from, tx_id, Some(amount.into()))
+        };
+        let available = |client| async move {
+            ledger
+                .account(client, NO_CURRENCY)
+                .await
+                .map(|account| account.available)
+        };
+
+        ledger
+            .apply(ParsedTransaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(10.into()),
+            ))
+            .await
+            .unwrap();
+        ledger
+            .apply(ParsedTransaction::new(
+                TransactionType::Deposit,
+                2,
+                2,
+                Some(5.into()),
+            ))
+            .await
+            .unwrap();
+
+        let outcome = ledger.apply(transfer(1, Some(2), 3, 4)).await.unwrap();
+        assert_eq!(outcome.account.available, Decimal::from(6));
+        assert_eq!(available(2).await, Some(Decimal::from(9)));
+
+        let rejected = [
+            (transfer(1, Some(2), 3, 1), Error::DuplicateTransaction),
+            (transfer(1, Some(2), 4, 7), Error::NoAvailableFunds),
+            (transfer(1, Some(2), 5, 0), Error::InvalidAmount),
+            (transfer(1, Some(1), 6, 1), Error::InvalidTransfer),
+            (transfer(1, None, 7, 1), Error::InvalidTransfer),
+            (transfer(1, Some(3), 8, 1), Error::RecipientNotFound),
+            (transfer(3, Some(1), 9, 1), Error::ClientNotFound),
+        ];
+        for (tx, error) in rejected {
+            assert_eq!(ledger.apply(tx).await, Err(error));
+        }
+
+        // Neither client changes if the recipient is locked
+        ledger.freeze(2, 10, "kyc").await.unwrap();
+        assert_eq!(
+            ledger.apply(transfer(1, Some(2), 11, 1)).await,
+            Err(Error::RecipientLocked)
+        );
+        assert_eq!(available(1).await, Some(Decimal::from(6)));
+        assert_eq!(available(2).await, Some(Decimal::from(9)));
+        ledger.unlock(2, 12, "cleared").await.unwrap();
+
+        // Opposite transfers running together neither wait on each other nor lose funds
+        let transfers = (0..100).map(|i| {
+            let (from, to) = if i % 2 == 0 { (1, 2) } else { (2, 1) };
+            ledger.apply(transfer(from, Some(to), 100 + i, 1))
+        });
+        for result in futures::future::join_all(transfers).await {
+            result.unwrap();
+        }
+        assert_eq!(available(1).await, Some(Decimal::from(6)));
+        assert_eq!(available(2).await, Some(Decimal::from(9)));
+
+        // Transfers are in the journals of both clients, and are still counted once
+        let trial = ledger.trial_balance().await.unwrap();
+        assert_eq!(
+            trial
+                .totals(NO_CURRENCY, journal::Account::Available(2))
+                .balance(),
+            Decimal::from(9)
+        );
+    }
+
+    // Workers on several threads, so their queues are really consumed concurrently
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_process_transfers() {
+        // Each transfer is followed by a withdrawal of the recipient, that only succeeds
+        //    once the transfer was applied
+        let mut input = String::from("type,client,tx,amount,to\ndeposit,1,1,1000,\n");
+        for client in 2..=6 {
+            input += &format!(
+                "deposit,{0},{0},1,\nwithdrawal,{0},{1},1,\n",
+                client,
+                client + 10
+            );
+        }
+        for i in 0..200 {
+            let to = 2 + i % 5;
+            input += &format!("transfer,1,{},1,{}\n", 100 + 2 * i, to);
+            input += &format!("withdrawal,{},{},1,\n", to, 101 + 2 * i);
+        }
+        let source = || Box::new(CsvSource::new(std::io::Cursor::new(input.clone())).unwrap());
+
+        let replayed = Ledger::new();
+        replayed.replay_source(source()).await.unwrap();
+        let expected = replayed.accounts().await;
+        assert_eq!(expected[0].available, Decimal::from(800));
+        assert!(expected[1..]
+            .iter()
+            .all(|account| account.available.is_zero()));
+
+        for workers in [1, 3, 8] {
+            let ledger = Ledger::new();
+            ledger.process_source(source(), workers).await.unwrap();
+            assert_eq!(ledger.accounts().await, expected, "{} workers", workers);
+        }
+    }
+}
@@ -0,0 +1,26 @@
+#[macro_use]
+extern crate log;
+
+pub mod audit;
+pub mod client;
+pub mod error;
+pub mod fees;
+pub mod journal;
+pub mod ledger;
+pub mod listener;
+pub mod pipeline;
+pub mod processor;
+pub mod rates;
+pub mod reader;
+pub mod rejections;
+pub mod server;
+pub mod snapshot;
+pub mod source;
+pub mod transaction;
+pub mod wal;
+pub mod writer;
+
+pub use client::{ClientID, SerializableClient};
+pub use error::Error;
+pub use ledger::{Ledger, Outcome};
+pub use transaction::{Currency, ParsedTransaction, TransactionID, TransactionType, NO_CURRENCY};
@@ -0,0 +1,319 @@
+use std::{future::Future, net::SocketAddr, sync::Arc};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot, watch},
+    task::JoinSet,
+};
+
+use crate::{
+    client::SerializableClient,
+    error::Error,
+    ledger::Ledger,
+    pipeline::Pipeline,
+    rejections::Rejection,
+    source::{parse_record, RecordLocation},
+};
+
+// Used when a connection doesn't start with its own header line
+const DEFAULT_HEADERS: [&str; 9] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "reason",
+    "currency",
+    "to_currency",
+    "timestamp",
+    "to",
+];
+
+// Longest line a connection can send, in bytes. A longer one is rejected as `RecordTooLong`,
+//    and closes the connection
+const MAX_LINE_LENGTH: u64 = 64 * 1024;
+
+enum Ack {
+    Ready(Result<(), Error>),
+    Queued(oneshot::Receiver<Result<SerializableClient, Error>>),
+}
+
+/// Accepts connections until `shutdown` completes. Each connection sends csv lines, with the
+/// same fields (and trimming) as the csv input, and an optional header line first.
+///
+/// Every line is acknowledged on the same connection, in order, with its line number:
+/// `<line>,accepted` once applied, or `<line>,rejected,<Error variant>`.
+/// Once `shutdown` completes, connections stop being read, and are closed once every line
+/// they already sent was acknowledged.
+pub async fn listen<F>(
+    listener: TcpListener,
+    ledger: &Ledger,
+    num_workers: u32,
+    shutdown: F,
+) -> Result<(), Error>
+where
+    F: Future<Output = ()>,
+{
+    let pipeline = Arc::new(ledger.pipeline(num_workers).await);
+    let mut connections = JoinSet::new();
+    let (stop, stopped) = watch::channel(false);
+
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    connections.spawn(handle_connection(
+                        stream,
+                        peer,
+                        Arc::clone(&pipeline),
+                        stopped.clone(),
+                    ));
+                }
+                Err(e) => debug!("Failed accepting connection: {:?}", e),
+            },
+            // Reap finished connections, so they don't pile up
+            Some(_) = connections.join_next(), if !connections.is_empty() => (),
+        }
+    }
+
+    // Open connections stop reading, and acknowledge what they already received
+    stop.send(true).ok();
+    while connections.join_next().await.is_some() {}
+
+    if Arc::strong_count(&pipeline) > 1 {
+        warn!("Pipeline still in use after closing all connections");
+    }
+    pipeline.shutdown().await;
+
+    Ok(())
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    peer: SocketAddr,
+    pipeline: Arc<Pipeline>,
+    mut stopped: watch::Receiver<bool>,
+) {
+    debug!("Accepted connection from {}", peer);
+    let (read, write) = stream.into_split();
+
+    let (acks, pending) = mpsc::unbounded_channel();
+    let writer = tokio::spawn(write_acks(BufWriter::new(write), pending));
+
+    let source = peer.to_string();
+    let mut reader = BufReader::new(read);
+    let mut buffer = vec![];
+    let mut headers = csv::StringRecord::from(DEFAULT_HEADERS.to_vec());
+    let (mut line_number, mut byte) = (0, 0);
+
+    loop {
+        buffer.clear();
+        // Reads one byte past the limit, to tell a line that's too long
+        let mut limited = (&mut reader).take(MAX_LINE_LENGTH + 1);
+        let read = tokio::select! {
+            read = limited.read_until(b'\n', &mut buffer) => read,
+            _ = stopped.wait_for(|stopped| *stopped) => break,
+        };
+        match read {
+            Ok(0) => break,
+            Ok(_) => (),
+            Err(e) => {
+                debug!("Failed reading from {}: {:?}", peer, e);
+                break;
+            }
+        }
+        line_number += 1;
+        let line_byte = byte;
+        byte += buffer.len() as u64;
+
+        if !buffer.ends_with(b"\n") && buffer.len() as u64 > MAX_LINE_LENGTH {
+            debug!("Line {} from {} is too long", line_number, peer);
+            acks.send((line_number, Ack::Ready(Err(Error::RecordTooLong))))
+                .ok();
+            break;
+        }
+        let line = String::from_utf8_lossy(&buffer);
+        let line = line.trim_end_matches(['\n', '\r']);
+
+        let record = match split_line(line) {
+            Some(record) => record,
+            None => continue,
+        };
+        // A header has nothing but column names, in any order
+        if line_number == 1 && record.iter().all(|field| DEFAULT_HEADERS.contains(&field)) {
+            headers = record;
+            continue;
+        }
+
+        let ack = match parse_record(&headers, &record) {
+            Ok(tx) => Ack::Queued(pipeline.enqueue(tx).await),
+            Err(e) => {
+                let location = RecordLocation {
+                    source: source.clone(),
+                    line: line_number,
+                    byte: line_byte,
+                    record: line.trim().to_string(),
+                };
+                pipeline
+                    .reject(Rejection::unparsed(location, &headers, &record, e.clone()))
+                    .await;
+                Ack::Ready(Err(e))
+            }
+        };
+
+        if acks.send((line_number, ack)).is_err() {
+            break;
+        }
+    }
+
+    drop(acks);
+    writer.await.ok();
+    debug!("Closed connection from {}", peer);
+}
+
+// Splits a csv line into trimmed fields, or `None` if it's empty
+fn split_line(line: &str) -> Option<csv::StringRecord> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .trim(csv::Trim::All)
+        .from_reader(line.as_bytes());
+
+    match reader.records().next()? {
+        Ok(record) => Some(record),
+        Err(_) => Some(csv::StringRecord::new()),
+    }
+}
+
+async fn write_acks<W: AsyncWrite + Unpin>(
+    mut out: W,
+    mut pending: mpsc::UnboundedReceiver<(u64, Ack)>,
+) {
+    while let Some((line, ack)) = pending.recv().await {
+        let result = match ack {
+            Ack::Ready(result) => result,
+            Ack::Queued(result) => result
+                .await
+                .unwrap_or(Err(Error::FailedPushingTx))
+                .map(|_| ()),
+        };
+        let ack = match result {
+            Ok(_) => format!("{},accepted\n", line),
+            Err(e) => format!("{},rejected,{:?}\n", line, e),
+        };
+
+        if out.write_all(ack.as_bytes()).await.is_err() {
+            return;
+        }
+        // Don't hold acks back while the client waits for them
+        if pending.is_empty() && out.flush().await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::NO_CURRENCY;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_listen() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let ledger = Arc::new(Ledger::new());
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = {
+            let ledger = Arc::clone(&ledger);
+            tokio::spawn(async move {
+                listen(listener, &ledger, 2, async {
+                    stopped.await.ok();
+                })
+                .await
+            })
+        };
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"type, client, tx, amount\n\
+                  deposit, 1, 1, 10.0\n\
+                  \n\
+                  withdrawal,1,2,11\n\
+                  deposit,1,3,a.5\n\
+                  dispute ,1,1,\n",
+            )
+            .await
+            .unwrap();
+        stream.shutdown().await.unwrap();
+
+        let mut acks = String::new();
+        stream.read_to_string(&mut acks).await.unwrap();
+        assert_eq!(
+            acks,
+            "2,accepted\n4,rejected,NoAvailableFunds\n5,rejected,InvalidAmount\n6,accepted\n"
+        );
+
+        // Without a header line, fields are in the default order
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"deposit,2,4,1\nfreeze,2,5,,kyc\n")
+            .await
+            .unwrap();
+        stream.shutdown().await.unwrap();
+        let mut acks = String::new();
+        stream.read_to_string(&mut acks).await.unwrap();
+        assert_eq!(acks, "1,accepted\n2,accepted\n");
+
+        // Header columns can be in any order
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"client,tx,type,amount\n3,6,deposit,2\n")
+            .await
+            .unwrap();
+        stream.shutdown().await.unwrap();
+        let mut acks = String::new();
+        stream.read_to_string(&mut acks).await.unwrap();
+        assert_eq!(acks, "2,accepted\n");
+
+        // Lines that are too long close the connection
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut long = "deposit,3,7,1".to_string();
+        long.push_str(&" ".repeat(MAX_LINE_LENGTH as usize + 1 - long.len()));
+        stream.write_all(long.as_bytes()).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut acks = String::new();
+        stream.read_to_string(&mut acks).await.unwrap();
+        assert_eq!(acks, "1,rejected,RecordTooLong\n");
+
+        // Connections still open on shutdown acknowledge what they sent, and are closed
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"deposit,3,8,1\n").await.unwrap();
+        let mut ack = [0; 11];
+        stream.read_exact(&mut ack).await.unwrap();
+        assert_eq!(&ack, b"1,accepted\n");
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        let mut acks = String::new();
+        stream.read_to_string(&mut acks).await.unwrap();
+        assert_eq!(acks, "");
+
+        let account = ledger.account(1, NO_CURRENCY).await.unwrap();
+        assert_eq!(account.held.to_string(), "10.0");
+        assert_eq!(ledger.accounts().await.len(), 3);
+        assert!(ledger.account(2, NO_CURRENCY).await.unwrap().locked);
+        assert_eq!(
+            ledger
+                .account(3, NO_CURRENCY)
+                .await
+                .unwrap()
+                .total
+                .to_string(),
+            "3"
+        );
+    }
+}
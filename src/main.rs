@@ -1,69 +1,370 @@
-#[macro_use]
-extern crate log;
+use std::{fs::File, process::ExitCode, sync::Arc};
 
-mod client;
-mod error;
-mod processor;
-mod reader;
-mod transaction;
-mod writer;
+use clap::{Args, Parser, Subcommand};
+use pay::audit::AuditWriter;
+use pay::client::{
+    policy::{DisputePolicy, LockPolicy, Rounding},
+    sqlite::SqliteStorage,
+};
+use pay::fees::FeeSchedule;
+use pay::rates::RateTable;
+use pay::rejections::RejectionsWriter;
+use pay::source::{self, ChainedSource, InputFormat, TransactionSource};
+use pay::writer::{write, write_deficits, write_revenue, write_to, OutputFormat, OutputOrder};
+use pay::{listener, server, Ledger};
+use tokio::net::TcpListener;
 
-use client::db::generate_client_db;
-use client::queue::{generate_clients_queues, CQReceivers, CQSenders};
-use client::ClientID;
+/// Processes client transactions and outputs the final balance of every client.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Processes transactions with concurrent workers, and outputs the final balances
+    Process(ProcessArgs),
+    /// Applies transactions one at a time, in input order, and outputs the final balances
+    Replay(ProcessArgs),
+    /// Checks that every record can be parsed, without processing it.
+    /// Invalid records are written as csv, and the exit code is non-zero if there are any.
+    Validate(InputArgs),
+    /// Serves the ledger over http: POST /transactions, GET /accounts and
+    /// GET /accounts/{client}. Stops on ctrl-c
+    Serve(ServeArgs),
+    /// Reads csv lines from tcp connections, acknowledging each line on the same connection
+    /// with `<line>,accepted` or `<line>,rejected,<error>`. Stops on ctrl-c
+    Listen(ListenArgs),
+}
+
+#[derive(Args)]
+struct InputArgs {
+    /// Input files, processed in order as a single stream. `-` reads from stdin
+    #[arg(required = true)]
+    files: Vec<String>,
+
+    /// Input format [csv, jsonl]. Guessed from the file extension by default
+    #[arg(long)]
+    input_format: Option<InputFormat>,
+
+    /// Where to write the output. Defaults to stdout
+    #[arg(long, short)]
+    output: Option<String>,
+
+    /// Stops at the first malformed record (eg. unknown type, bad amount), with a non-zero
+    /// exit code and its location
+    #[arg(long)]
+    strict: bool,
+}
+
+#[derive(Args)]
+struct ProcessArgs {
+    #[command(flatten)]
+    input: InputArgs,
+
+    /// Number of concurrent workers
+    #[arg(long, env = "NUM_WORKERS", default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    workers: u32,
+
+    /// Output format [csv, json, jsonl]
+    #[arg(long, short, default_value = "csv")]
+    format: OutputFormat,
+
+    /// Order of the output rows [client, insertion]
+    #[arg(long, default_value = "client")]
+    order: OutputOrder,
+
+    /// Also writes the clients whose available funds went negative to this file,
+    /// in the output format
+    #[arg(long)]
+    deficits: Option<String>,
+
+    /// Also writes the fees the house collected, per currency, to this file, in the
+    /// output format
+    #[arg(long)]
+    revenue: Option<String>,
+
+    #[command(flatten)]
+    state: StateArgs,
+}
+
+#[derive(Args)]
+struct ServeArgs {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    addr: String,
+
+    /// Rejects a whole batch of transactions if any of them is malformed, instead of
+    /// only rejecting those
+    #[arg(long)]
+    strict: bool,
+
+    /// Number of concurrent workers
+    #[arg(long, env = "NUM_WORKERS", default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    workers: u32,
+
+    #[command(flatten)]
+    state: StateArgs,
+}
+
+#[derive(Args)]
+struct ListenArgs {
+    /// Address to listen on
+    #[arg(long)]
+    addr: String,
+
+    /// Number of concurrent workers
+    #[arg(long, env = "NUM_WORKERS", default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    workers: u32,
+
+    #[command(flatten)]
+    state: StateArgs,
+}
+
+#[derive(Args)]
+struct StateArgs {
+    /// Writes every skipped transaction, and why, to this csv file
+    #[arg(long)]
+    rejections: Option<String>,
+
+    /// Writes the outcome of every transaction, and the balances of its client after it,
+    /// to this csv file
+    #[arg(long)]
+    audit: Option<String>,
+
+    /// Restores the state saved by a previous run (with --save-state) before processing
+    #[arg(long)]
+    load_state: Option<String>,
+
+    /// Keeps the state in this sqlite database instead of in memory. The database is created
+    /// if missing, and the run continues from the state already in it otherwise
+    #[arg(long, conflicts_with_all = ["load_state", "wal"])]
+    sqlite: Option<String>,
+
+    /// Saves the complete state after processing, to be restored with --load-state
+    #[arg(long)]
+    save_state: Option<String>,
+
+    /// Logs every transaction to this file before applying it. If the file has entries
+    /// from a run that didn't finish, they are recovered and their records skipped
+    #[arg(long)]
+    wal: Option<String>,
+
+    /// Transaction types a locked client still accepts, eg. `deposit,resolve,chargeback`.
+    /// `unlock` is always accepted
+    #[arg(long, env = "LOCKED_ALLOW")]
+    locked_allow: Option<LockPolicy>,
+
+    /// How much of a deposit disputes hold [available, full]. `full` holds the whole
+    /// disputed amount even if it was already withdrawn, letting available go negative
+    #[arg(long, env = "DISPUTE_HOLD", default_value = "available")]
+    dispute_hold: DisputePolicy,
+
+    /// Path of a csv of exchange rates (`from,to,rate,effective`, with unix timestamps)
+    /// that `convert` transactions are made at. Without it, conversions are rejected
+    #[arg(long, env = "RATES")]
+    rates: Option<String>,
+
+    /// How converted amounts are rounded [half-even, half-up, half-down, down, up],
+    /// optionally followed by the decimal places, eg. `down:2`
+    #[arg(long, env = "CONVERT_ROUNDING", default_value = "half-even:4")]
+    convert_rounding: Rounding,
 
-use processor::start_processors;
-use reader::{start_reader, ReadingStatus};
+    /// Path of a csv of fees (`type,flat,percentage,min,max`) charged on withdrawals
+    /// and chargebacks, which go to the house revenue account
+    #[arg(long, env = "FEES")]
+    fees: Option<String>,
+}
+
+type CliResult = Result<ExitCode, Box<dyn std::error::Error>>;
+
+fn open_sources(args: &InputArgs) -> Result<Box<dyn TransactionSource + Send>, String> {
+    let sources = args
+        .files
+        .iter()
+        .map(|path| source::open(path, args.input_format).map_err(|e| format!("{}: {}", path, e)))
+        .collect::<Result<Vec<_>, String>>()?;
+
+    Ok(Box::new(ChainedSource::new(sources)))
+}
+
+async fn open_ledger(args: &StateArgs, strict: bool) -> Result<Ledger, String> {
+    let ledger = match (&args.load_state, &args.sqlite) {
+        (Some(path), _) => Ledger::load_state(path).map_err(|e| format!("{}: {}", path, e))?,
+        (_, Some(path)) => {
+            let storage = SqliteStorage::open(path).map_err(|e| format!("{}: {}", path, e))?;
+            Ledger::from_storage(Arc::new(storage))
+        }
+        (None, None) => Ledger::new(),
+    };
+    let mut ledger = ledger
+        .with_strict(strict)
+        .with_lock_policy(args.locked_allow.clone().unwrap_or_default())
+        .with_dispute_policy(args.dispute_hold)
+        .with_rounding(args.convert_rounding);
+    if let Some(path) = &args.rates {
+        let rates = RateTable::from_path(path).map_err(|e| format!("{}: {}", path, e))?;
+        ledger = ledger.with_rates(rates);
+    }
+    if let Some(path) = &args.fees {
+        let fees = FeeSchedule::from_path(path).map_err(|e| format!("{}: {}", path, e))?;
+        ledger = ledger.with_fees(fees);
+    }
+    if let Some(path) = &args.wal {
+        ledger = ledger
+            .with_wal(path)
+            .await
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    if let Some(path) = &args.rejections {
+        let rejections =
+            RejectionsWriter::from_path(path).map_err(|e| format!("{}: {}", path, e))?;
+        ledger = ledger.with_rejections(rejections);
+    }
+    if let Some(path) = &args.audit {
+        let audit = AuditWriter::from_path(path).map_err(|e| format!("{}: {}", path, e))?;
+        ledger = ledger.with_audit(audit);
+    }
+
+    Ok(ledger)
+}
+
+async fn save_state(args: &StateArgs, ledger: &Ledger) -> Result<(), String> {
+    match &args.save_state {
+        Some(path) => ledger
+            .save_state(path)
+            .await
+            .map_err(|e| format!("{}: {}", path, e)),
+        None => Ok(()),
+    }
+}
+
+async fn process(args: ProcessArgs, sequential: bool) -> CliResult {
+    let source = open_sources(&args.input)?;
+    let ledger = open_ledger(&args.state, args.input.strict).await?;
+
+    if sequential {
+        ledger.replay_source(source).await?;
+    } else {
+        ledger.process_source(source, args.workers).await?;
+    }
+
+    // Balances that don't match the journal of their client are neither saved nor output
+    ledger.trial_balance().await?;
+    save_state(&args.state, &ledger).await?;
+
+    match &args.input.output {
+        Some(path) => {
+            let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+            write_to(ledger.storage(), args.order, args.format, file).await?
+        }
+        None => write(ledger.storage(), args.order, args.format).await?,
+    };
+
+    if let Some(path) = &args.deficits {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        write_deficits(ledger.storage(), args.format, file).await?;
+    }
+
+    if let Some(path) = &args.revenue {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        write_revenue(ledger.storage(), args.format, file).await?;
+    }
+
+    ledger.finish().await?;
+    Ok(ExitCode::SUCCESS)
+}
 
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use writer::write;
+async fn validate(args: InputArgs) -> CliResult {
+    let mut source = open_sources(&args)?;
+
+    let rejections = match &args.output {
+        Some(path) => RejectionsWriter::from_path(path).map_err(|e| format!("{}: {}", path, e))?,
+        None => RejectionsWriter::new(Box::new(std::io::stdout())),
+    };
+
+    let (mut records, mut invalid) = (0, 0);
+    while let Some(tx) = source.next_transaction() {
+        records += 1;
+        if let Err(rejection) = tx {
+            invalid += 1;
+            rejections.record(rejection.clone()).await;
+
+            if args.strict || rejection.fatal {
+                rejections.flush().await;
+                return Err(rejection.into_error().into());
+            }
+        }
+    }
+    rejections.flush().await;
+
+    eprintln!("{} records, {} invalid", records, invalid);
+    if invalid > 0 {
+        Ok(ExitCode::FAILURE)
+    } else {
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+async fn serve(args: ServeArgs) -> CliResult {
+    let ledger = Arc::new(open_ledger(&args.state, args.strict).await?);
+
+    let listener = TcpListener::bind(&args.addr)
+        .await
+        .map_err(|e| format!("{}: {}", args.addr, e))?;
+    eprintln!("Listening on {}", args.addr);
+
+    let shutdown = async {
+        tokio::signal::ctrl_c().await.ok();
+    };
+    server::serve(listener, Arc::clone(&ledger), args.workers, shutdown).await?;
+
+    ledger.trial_balance().await?;
+    save_state(&args.state, &ledger).await?;
+    ledger.finish().await?;
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn listen(args: ListenArgs) -> CliResult {
+    // Every line is acknowledged on its own, so there's nothing for strict mode to stop
+    let ledger = open_ledger(&args.state, false).await?;
+
+    let listener = TcpListener::bind(&args.addr)
+        .await
+        .map_err(|e| format!("{}: {}", args.addr, e))?;
+    eprintln!("Listening on {}", args.addr);
+
+    let shutdown = async {
+        tokio::signal::ctrl_c().await.ok();
+    };
+    listener::listen(listener, &ledger, args.workers, shutdown).await?;
+
+    ledger.trial_balance().await?;
+    save_state(&args.state, &ledger).await?;
+    ledger.finish().await?;
+    Ok(ExitCode::SUCCESS)
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> ExitCode {
     env_logger::init();
-    let args: Vec<String> = std::env::args().collect();
-    let file_in = args.get(1).unwrap();
-
-    let reading_status = Arc::new(RwLock::new(ReadingStatus::new()));
-    let client_db = Arc::new(generate_client_db());
-
-    let (notifier_sender, notifier_receiver): (
-        async_channel::Sender<ClientID>,
-        async_channel::Receiver<ClientID>,
-    ) = async_channel::unbounded();
-
-    let (senders, receivers): (CQSenders, CQReceivers) = generate_clients_queues();
-    let pile_senders = Arc::new(senders);
-    let pile_receivers = Arc::new(receivers);
-
-    let num_processors = std::env::var("NUM_WORKERS")
-        .unwrap_or("3".to_string())
-        .parse::<u32>()
-        .unwrap_or(3);
-
-    let processors = start_processors(
-        num_processors,
-        &reading_status,
-        &notifier_receiver,
-        &client_db,
-        &pile_receivers,
-    )
-    .await;
-
-    start_reader(
-        file_in.clone(),
-        &reading_status,
-        &notifier_sender,
-        &pile_senders,
-        &pile_receivers,
-    )
-    .await;
-
-    notifier_sender.close();
-    futures::future::join_all(processors).await;
-
-    write(client_db).await;
-
-    Ok(())
+    let cli = Cli::parse();
+
+    let result = match cli.command {
+        Command::Process(args) => process(args, false).await,
+        Command::Replay(args) => process(args, true).await,
+        Command::Validate(args) => validate(args).await,
+        Command::Serve(args) => serve(args).await,
+        Command::Listen(args) => listen(args).await,
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("pay: {}", e);
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use async_channel::Sender;
+use tokio::{
+    sync::{oneshot, RwLock},
+    task::JoinHandle,
+};
+
+use crate::{
+    client::{
+        manager::ClientsManager,
+        queue::{
+            generate_clients_queues, push_tx, CQReceivers, CQSenders, QueuedTransaction, Replies,
+        },
+        ClientID, SerializableClient,
+    },
+    error::Error,
+    processor::start_processors,
+    reader::{ReadingStatus, ReadingStatusTypes},
+    rejections::{Rejection, RejectionsWriter},
+    transaction::ParsedTransaction,
+};
+
+/// Long-running processors, fed one transaction at a time instead of by a reader.
+///
+/// Transactions go through the same per-client queues as in `Ledger::process_source`,
+/// so the transactions of a client (including transfers to it) are applied in the order
+/// they were submitted, even when submitted concurrently.
+pub struct Pipeline {
+    status: Arc<RwLock<ReadingStatus>>,
+    notifier: Sender<ClientID>,
+    senders: Arc<CQSenders>,
+    receivers: Arc<CQReceivers>,
+    replies: Arc<Replies>,
+    rejections: Option<Arc<RejectionsWriter>>,
+    manager: ClientsManager,
+    // Taken by `shutdown`
+    processors: Mutex<Vec<JoinHandle<()>>>,
+    // Submitted transactions are numbered like the rows of an input
+    next_row: AtomicU64,
+}
+
+impl Pipeline {
+    pub async fn start(
+        manager: &ClientsManager,
+        rejections: &Option<Arc<RejectionsWriter>>,
+        num_workers: u32,
+    ) -> Pipeline {
+        let status = Arc::new(RwLock::new(ReadingStatus::new()));
+        status.write().await.change(ReadingStatusTypes::InProgress);
+
+        let (notifier, notifier_receiver) = async_channel::unbounded();
+        let (senders, receivers): (CQSenders, CQReceivers) = generate_clients_queues();
+        let senders = Arc::new(senders);
+        let receivers = Arc::new(receivers);
+        let replies = Arc::new(Mutex::new(HashMap::new()));
+
+        let processors = start_processors(
+            num_workers,
+            &status,
+            &notifier_receiver,
+            manager,
+            &receivers,
+            rejections,
+            &Some(Arc::clone(&replies)),
+        )
+        .await;
+
+        Pipeline {
+            status,
+            notifier,
+            senders,
+            receivers,
+            replies,
+            rejections: rejections.clone(),
+            manager: manager.clone(),
+            processors: Mutex::new(processors),
+            next_row: AtomicU64::new(1),
+        }
+    }
+
+    /// Queues `tx`, returning where its result will be sent once it's applied (or rejected):
+    /// the state of its client right after it, in the currency it moved funds in.
+    pub async fn enqueue(
+        &self,
+        tx: ParsedTransaction,
+    ) -> oneshot::Receiver<Result<SerializableClient, Error>> {
+        let row = self.next_row.fetch_add(1, Ordering::Relaxed);
+
+        let (reply, result) = oneshot::channel();
+        if let Ok(mut replies) = self.replies.lock() {
+            replies.insert(row, reply);
+        }
+
+        let clients = push_tx(
+            &self.senders,
+            &self.receivers,
+            QueuedTransaction::new(row, tx),
+        )
+        .await;
+
+        for client_id in clients {
+            if let Err(e) = self.notifier.send(client_id).await {
+                debug!("E2 {:?}", e);
+            }
+        }
+
+        result
+    }
+
+    /// Queues `tx` and waits for it to be applied.
+    pub async fn submit(&self, tx: ParsedTransaction) -> Result<SerializableClient, Error> {
+        self.enqueue(tx)
+            .await
+            .await
+            .unwrap_or(Err(Error::FailedPushingTx))
+    }
+
+    /// Records a transaction that couldn't be queued (eg. it couldn't be parsed).
+    pub async fn reject(&self, rejection: Rejection) {
+        if let Some(rejections) = &self.rejections {
+            rejections.record(rejection).await;
+        }
+    }
+
+    /// Waits for all queued transactions to be applied, and stops the processors.
+    /// Transactions submitted afterwards are rejected with `FailedPushingTx`.
+    pub async fn shutdown(&self) {
+        self.status.write().await.change(ReadingStatusTypes::Done);
+        self.notifier.close();
+        let processors = match self.processors.lock() {
+            Ok(mut processors) => std::mem::take(&mut *processors),
+            Err(_) => return,
+        };
+        futures::future::join_all(processors).await;
+
+        if let Some(rejections) = &self.rejections {
+            rejections.flush().await;
+        }
+        self.manager.flush().await;
+    }
+}
@@ -1,55 +1,71 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 use async_channel::Receiver;
-use futures_lite::future;
 use tokio::sync::RwLock;
 
 use crate::{
     client::{
-        db::ClientsDB,
         manager::ClientsManager,
-        queue::{consume, CQReceivers},
+        queue::{consume, CQReceivers, Replies, Transfers},
         ClientID,
     },
     reader::{ReadingStatus, ReadingStatusTypes},
+    rejections::RejectionsWriter,
 };
 
 pub async fn start_processors(
     max_processors: u32,
     reading_status: &Arc<RwLock<ReadingStatus>>,
     notification_receiver: &Receiver<ClientID>,
-    clients: &Arc<ClientsDB>,
+    clients_manager: &ClientsManager,
     receivers: &Arc<CQReceivers>,
+    rejections: &Option<Arc<RejectionsWriter>>,
+    replies: &Option<Arc<Replies>>,
 ) -> Vec<tokio::task::JoinHandle<()>> {
+    let transfers: Arc<Transfers> = Arc::new(Mutex::new(HashMap::new()));
+
     let mut threads = vec![];
     for _ in 0..max_processors {
         let status = Arc::clone(reading_status);
         let notifier = notification_receiver.clone();
-        let client_db = Arc::clone(clients);
+        let clients_manager = clients_manager.clone();
         let pile_receivers = Arc::clone(receivers);
+        let rejections = rejections.clone();
+        let replies = replies.clone();
+        let transfers = Arc::clone(&transfers);
 
         threads.push(tokio::spawn(async move {
-            let clients_manager = ClientsManager::new(client_db);
             loop {
                 {
                     match status.read().await.get() {
-                        ReadingStatusTypes::Aborted | ReadingStatusTypes::Done => {
-                            if notifier.is_empty() {
-                                break;
-                            }
+                        ReadingStatusTypes::Aborted | ReadingStatusTypes::Done
+                            if notifier.is_empty() =>
+                        {
+                            break;
                         }
                         _ => (),
                     };
                 }
 
-                let client_id = match future::block_on(notifier.recv()) {
+                let client_id = match notifier.recv().await {
                     Ok(client_id) => client_id,
                     Err(_) => {
                         continue;
                     }
                 };
 
-                consume(&pile_receivers, client_id, &clients_manager).await;
+                consume(
+                    &pile_receivers,
+                    client_id,
+                    &clients_manager,
+                    &rejections,
+                    &replies,
+                    &transfers,
+                )
+                .await;
             }
         }));
     }
@@ -0,0 +1,117 @@
+use std::{collections::BTreeMap, fs::File, io::Read};
+
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::{error::Error, transaction::Currency};
+
+/// Seconds since the unix epoch.
+pub type Timestamp = u64;
+
+#[derive(Deserialize, Debug)]
+struct RateRecord {
+    #[serde(deserialize_with = "crate::transaction::to_currency_code")]
+    from: Currency,
+    #[serde(deserialize_with = "crate::transaction::to_currency_code")]
+    to: Currency,
+    rate: Decimal,
+    effective: Timestamp,
+}
+
+/// Exchange rates between currencies, each effective from a timestamp until the next one
+/// for the same pair.
+///
+/// Rates only apply in the direction they are listed: converting back needs its own row.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateTable {
+    rates: BTreeMap<(Currency, Currency), BTreeMap<Timestamp, Decimal>>,
+}
+
+impl RateTable {
+    pub fn new() -> RateTable {
+        RateTable::default()
+    }
+
+    /// Reads a csv with a `from,to,rate,effective` header, eg. `EUR,USD,1.0842,1700000000`.
+    pub fn from_reader<R: Read>(reader: R) -> Result<RateTable, Error> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+
+        reader
+            .deserialize()
+            .try_fold(RateTable::new(), |table, record| {
+                let record: RateRecord = record.map_err(|_| Error::InvalidRecord)?;
+                if record.rate <= Decimal::ZERO || record.from == record.to {
+                    Err(Error::InvalidRecord)
+                } else {
+                    Ok(table.with_rate(&record.from, &record.to, record.rate, record.effective))
+                }
+            })
+    }
+
+    pub fn from_path(path: &str) -> Result<RateTable, Error> {
+        let file = File::open(path).map_err(|_| Error::UnknownFile)?;
+        RateTable::from_reader(file)
+    }
+
+    /// Converts `from` into `to` at `rate`, starting at `effective`.
+    pub fn with_rate(
+        mut self,
+        from: &str,
+        to: &str,
+        rate: Decimal,
+        effective: Timestamp,
+    ) -> RateTable {
+        self.rates
+            .entry((from.to_string(), to.to_string()))
+            .or_default()
+            .insert(effective, rate);
+        self
+    }
+
+    /// Rate converting `from` into `to` at `at`, or the latest one if `at` is unknown.
+    pub fn rate(&self, from: &str, to: &str, at: Option<Timestamp>) -> Option<Decimal> {
+        let rates = self.rates.get(&(from.to_string(), to.to_string()))?;
+        rates
+            .range(..=at.unwrap_or(Timestamp::MAX))
+            .next_back()
+            .map(|(_, rate)| *rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn test_rate_table() {
+        let rates = "from,to,rate,effective
+eur,usd,1.08,100
+EUR, USD,1.10,200
+USD,EUR,0.9,100
+";
+        let table = RateTable::from_reader(rates.as_bytes()).unwrap();
+        let rate = |value| Some(Decimal::from_str(value).unwrap());
+
+        assert_eq!(table.rate("EUR", "USD", Some(99)), None);
+        assert_eq!(table.rate("EUR", "USD", Some(100)), rate("1.08"));
+        assert_eq!(table.rate("EUR", "USD", Some(199)), rate("1.08"));
+        assert_eq!(table.rate("EUR", "USD", Some(200)), rate("1.10"));
+        assert_eq!(table.rate("EUR", "USD", None), rate("1.10"));
+        assert_eq!(table.rate("USD", "EUR", Some(150)), rate("0.9"));
+        assert_eq!(table.rate("EUR", "GBP", None), None);
+
+        for invalid in [
+            "from,to,rate,effective\nEUR,USD,0,100\n",
+            "from,to,rate,effective\nEUR,EUR,1,100\n",
+            "from,to,rate,effective\nEUR,USD,abc,100\n",
+        ] {
+            assert_eq!(
+                RateTable::from_reader(invalid.as_bytes()),
+                Err(Error::InvalidRecord)
+            );
+        }
+    }
+}
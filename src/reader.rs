@@ -6,10 +6,10 @@ use crate::{
         ClientID,
     },
     error::Error,
-    transaction::ParsedTransaction,
+    rejections::RejectionsWriter,
+    source::TransactionSource,
 };
 use async_channel::Sender;
-use csv;
 use tokio::sync::RwLock;
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -37,31 +37,42 @@ impl ReadingStatus {
     }
 
     pub fn get(&self) -> ReadingStatusTypes {
-        self.status.clone()
+        self.status
+    }
+}
+
+impl Default for ReadingStatus {
+    fn default() -> Self {
+        ReadingStatus::new()
     }
 }
 
 pub async fn start_reader(
-    file_in: String,
+    source: Box<dyn TransactionSource + Send>,
     status: &Arc<RwLock<ReadingStatus>>,
     notification_sender: &Sender<ClientID>,
     senders: &Arc<CQSenders>,
     receivers: &Arc<CQReceivers>,
-) {
-    let reader_status = Arc::clone(&status);
+    rejections: &Option<Arc<RejectionsWriter>>,
+    strict: bool,
+) -> Result<(), Error> {
+    let reader_status = Arc::clone(status);
     let notification_sender = notification_sender.clone();
     let senders = senders.clone();
     let receivers = receivers.clone();
 
-    match read_file(
-        file_in.as_str(),
+    let result = read_source(
+        source,
         &reader_status,
         &notification_sender,
         &senders,
         &receivers,
+        rejections,
+        strict,
     )
-    .await
-    {
+    .await;
+
+    match result {
         Ok(_) => reader_status.write().await.change(ReadingStatusTypes::Done),
         Err(_) => reader_status
             .write()
@@ -72,46 +83,45 @@ pub async fn start_reader(
         "Finished reading with status: {:?}",
         reader_status.read().await.get()
     );
+
+    result
 }
 
-async fn read_file(
-    file_in: &str,
+async fn read_source(
+    mut source: Box<dyn TransactionSource + Send>,
     status: &Arc<RwLock<ReadingStatus>>,
     notification_sender: &Sender<ClientID>,
     senders: &Arc<CQSenders>,
     receivers: &Arc<CQReceivers>,
+    rejections: &Option<Arc<RejectionsWriter>>,
+    strict: bool,
 ) -> Result<(), Error> {
-    let mut reader = csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .from_path(file_in)
-        .map_err(|_| Error::UnknownFile)?;
-
     status.write().await.change(ReadingStatusTypes::InProgress);
 
-    let mut record = 0 as u64;
-    for tx in reader.deserialize() {
-        let tx: ParsedTransaction = match tx {
+    let mut record: u64 = 0;
+    while let Some(tx) = source.next_transaction() {
+        let tx = match tx {
             Ok(tx) => tx,
-            Err(e) => {
-                debug!("E1 {:?}", e);
+            Err(rejection) => {
+                if let Some(rejections) = rejections {
+                    rejections.record(rejection.clone()).await;
+                }
+                // In strict mode, a record that can't be parsed aborts the whole input
+                if strict || rejection.fatal {
+                    return Err(rejection.into_error());
+                }
                 continue;
             }
         };
 
-        let client_id = tx.client_id;
-
-        push_tx(senders, receivers, tx).await;
-
-        match notification_sender.send(client_id).await {
-            Ok(_) => (),
-            Err(e) => {
+        for client_id in push_tx(senders, receivers, tx).await {
+            if let Err(e) = notification_sender.send(client_id).await {
                 debug!("E2 {:?}", e);
-                continue;
             }
-        };
+        }
         record += 1;
 
-        if record % 100_000 == 0 {
+        if record.is_multiple_of(100_000) {
             debug!(
                 "State: Parsed {} | On Queue {}",
                 record,
@@ -0,0 +1,168 @@
+use std::{fs::File, io::Write, sync::Arc};
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::{
+    client::ClientID,
+    error::Error,
+    source::RecordLocation,
+    transaction::{ParsedTransaction, TransactionID, TransactionType},
+};
+
+/// A transaction that did not change any state, and why.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Rejection {
+    pub row: u64,
+    pub client: Option<ClientID>,
+    pub tx: Option<TransactionID>,
+    #[serde(rename = "type")]
+    pub tx_type: Option<TransactionType>,
+    pub error: String,
+    /// Input the transaction was read from (eg. its path), if any
+    pub source: Option<String>,
+    // Only set for records that could not be read
+    #[serde(skip)]
+    pub location: Option<Box<RecordLocation>>,
+    // Whether the rest of the input can't be read either
+    #[serde(skip)]
+    pub fatal: bool,
+}
+
+impl Rejection {
+    pub fn new(row: u64, tx: &ParsedTransaction, error: &Error) -> Rejection {
+        Rejection {
+            row,
+            client: Some(tx.client_id),
+            tx: Some(tx.tx_id),
+            tx_type: Some(tx.tx_type.clone()),
+            error: format!("{:?}", error),
+            source: None,
+            location: None,
+            fatal: false,
+        }
+    }
+
+    pub fn with_source(mut self, source: Option<Arc<str>>) -> Rejection {
+        self.source = source.map(|source| source.to_string());
+        self
+    }
+
+    /// Rejection for a record that could not be deserialized.
+    /// Fields are filled in on a best-effort basis from the raw record.
+    pub fn unparsed(
+        location: RecordLocation,
+        headers: &csv::StringRecord,
+        record: &csv::StringRecord,
+        error: Error,
+    ) -> Rejection {
+        let field = |name: &str| {
+            headers
+                .iter()
+                .position(|h| h == name)
+                .and_then(|i| record.get(i))
+        };
+
+        Rejection {
+            row: location.line,
+            client: field("client").and_then(|v| v.parse().ok()),
+            tx: field("tx").and_then(|v| v.parse().ok()),
+            tx_type: field("type").and_then(TransactionType::from_name),
+            error: format!("{:?}", error),
+            source: Some(location.source.clone()),
+            location: Some(Box::new(location)),
+            fatal: false,
+        }
+    }
+
+    /// Rejection for an input that failed to be read at `location` (eg. an I/O error),
+    /// which always aborts it.
+    pub fn unreadable(location: RecordLocation) -> Rejection {
+        Rejection {
+            row: location.line,
+            client: None,
+            tx: None,
+            tx_type: None,
+            error: "FailedReading".to_string(),
+            source: Some(location.source.clone()),
+            location: Some(Box::new(location)),
+            fatal: true,
+        }
+    }
+
+    /// Error to abort with, in strict mode (or always, if it's fatal).
+    pub fn into_error(self) -> Error {
+        match (self.location, self.fatal) {
+            (Some(location), true) => Error::FailedReading(location),
+            (Some(location), false) => Error::MalformedRecord(location),
+            (None, _) => Error::InvalidRecord,
+        }
+    }
+}
+
+/// Shared csv sink for rejections, written to by the reader and all processors.
+pub struct RejectionsWriter {
+    writer: Mutex<csv::Writer<Box<dyn Write + Send>>>,
+}
+
+impl RejectionsWriter {
+    pub fn new(writer: Box<dyn Write + Send>) -> RejectionsWriter {
+        RejectionsWriter {
+            writer: Mutex::new(csv::Writer::from_writer(writer)),
+        }
+    }
+
+    pub fn from_path(path: &str) -> Result<RejectionsWriter, Error> {
+        let file = File::create(path).map_err(|_| Error::UnknownFile)?;
+        Ok(RejectionsWriter::new(Box::new(file)))
+    }
+
+    pub async fn record(&self, rejection: Rejection) {
+        if let Err(e) = self.writer.lock().await.serialize(&rejection) {
+            debug!("Failed writing rejection {:?}: {:?}", rejection, e);
+        }
+    }
+
+    pub async fn flush(&self) {
+        if let Err(e) = self.writer.lock().await.flush() {
+            debug!("Failed flushing rejections: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_unparsed() {
+        let headers = csv::StringRecord::from(vec!["type", "client", "tx", "amount"]);
+        let record = csv::StringRecord::from(vec!["deposit", "d2", "1", "a.4"]);
+        let location = RecordLocation {
+            source: "test.csv".to_string(),
+            line: 3,
+            byte: 40,
+            record: "deposit,d2,1,a.4".to_string(),
+        };
+
+        let rejection =
+            Rejection::unparsed(location.clone(), &headers, &record, Error::InvalidRecord);
+        assert_eq!(
+            rejection,
+            Rejection {
+                row: 3,
+                client: None,
+                tx: Some(1),
+                tx_type: Some(TransactionType::Deposit),
+                error: "InvalidRecord".to_string(),
+                source: Some("test.csv".to_string()),
+                location: Some(Box::new(location.clone())),
+                fatal: false,
+            }
+        );
+        assert_eq!(
+            rejection.into_error(),
+            Error::MalformedRecord(Box::new(location))
+        );
+    }
+}
@@ -0,0 +1,338 @@
+use std::{future::Future, sync::Arc};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+
+use crate::{
+    client::{ClientID, SerializableClient},
+    error::Error,
+    ledger::Ledger,
+    pipeline::Pipeline,
+    source::parse_transaction,
+    transaction::TransactionID,
+};
+
+struct AppState {
+    ledger: Arc<Ledger>,
+    pipeline: Arc<Pipeline>,
+}
+
+/// Result of a submitted transaction.
+#[derive(Serialize, Debug)]
+struct Submitted {
+    // Missing if the transaction couldn't be parsed, and didn't have a valid one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client: Option<ClientID>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tx: Option<TransactionID>,
+    /// `applied` or `rejected`
+    status: &'static str,
+    /// `Error` variant the transaction was rejected with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// State of the client once the transaction was applied, in the currency it moved funds in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    account: Option<SerializableClient>,
+}
+
+/// Serves the ledger over http until `shutdown` completes:
+/// * `POST /transactions`: one transaction object, or an array of them, with the same fields
+///   as the csv. Transactions are applied with `num_workers` processors, in submission
+///   order for each client. If the ledger is strict, nothing in an array is applied unless
+///   all of it can be parsed; otherwise, only what can't be parsed is rejected.
+/// * `GET /accounts/{client}` / `GET /accounts`: current state of one/all clients,
+///   one entry per client and currency.
+pub async fn serve<F>(
+    listener: TcpListener,
+    ledger: Arc<Ledger>,
+    num_workers: u32,
+    shutdown: F,
+) -> Result<(), Error>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let pipeline = Arc::new(ledger.pipeline(num_workers).await);
+    let state = Arc::new(AppState {
+        ledger,
+        pipeline: Arc::clone(&pipeline),
+    });
+
+    let app = Router::new()
+        .route("/transactions", post(post_transactions))
+        .route("/accounts", get(get_accounts))
+        .route("/accounts/:client", get(get_account))
+        .with_state(state);
+
+    let result = axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown)
+        .await;
+
+    // Connections are closed by now, so nothing else should hold the pipeline. If anything
+    //    still does, what it submits from now on is rejected
+    if Arc::strong_count(&pipeline) > 1 {
+        warn!("Pipeline still in use after shutting down the server");
+    }
+    pipeline.shutdown().await;
+
+    result.map_err(|e| {
+        debug!("Failed serving: {:?}", e);
+        Error::FailedServing
+    })
+}
+
+fn error_response(status: StatusCode, error: Error, index: Option<usize>) -> Response {
+    let mut body = json!({ "error": format!("{:?}", error) });
+    if let Some(index) = index {
+        body["index"] = json!(index);
+    }
+    (status, Json(body)).into_response()
+}
+
+async fn post_transactions(State(state): State<Arc<AppState>>, body: Bytes) -> Response {
+    let value: Value = match serde_json::from_slice(&body) {
+        Ok(value) => value,
+        Err(_) => return error_response(StatusCode::BAD_REQUEST, Error::InvalidRecord, None),
+    };
+
+    let (batch, mut values) = match value {
+        Value::Array(values) => (true, values),
+        value => (false, vec![value]),
+    };
+    let strict = state.ledger.strict();
+    let mut txs = Vec::with_capacity(values.len());
+    for (index, value) in values.iter_mut().enumerate() {
+        match parse_transaction(value) {
+            Ok(tx) => txs.push(Ok(tx)),
+            Err(e) if batch && !strict => txs.push(Err(rejected(value, e))),
+            Err(e) => {
+                return error_response(StatusCode::BAD_REQUEST, e, batch.then_some(index));
+            }
+        }
+    }
+
+    // Queue the whole batch before waiting, so it keeps its order
+    let mut pending = Vec::with_capacity(txs.len());
+    for tx in txs {
+        pending.push(match tx {
+            Ok(tx) => {
+                let result = state.pipeline.enqueue(tx.clone()).await;
+                Ok((tx, result))
+            }
+            Err(rejected) => Err(rejected),
+        });
+    }
+
+    let mut results = Vec::with_capacity(pending.len());
+    for pending in pending {
+        let (tx, result) = match pending {
+            Ok(pending) => pending,
+            Err(rejected) => {
+                results.push(rejected);
+                continue;
+            }
+        };
+        // The state of the client right after this transaction, whatever came after it
+        let result = result.await.unwrap_or(Err(Error::FailedPushingTx));
+        results.push(match result {
+            Ok(account) => Submitted {
+                client: Some(tx.client_id),
+                tx: Some(tx.tx_id),
+                status: "applied",
+                error: None,
+                account: Some(account),
+            },
+            Err(e) => Submitted {
+                client: Some(tx.client_id),
+                tx: Some(tx.tx_id),
+                status: "rejected",
+                error: Some(format!("{:?}", e)),
+                account: None,
+            },
+        });
+    }
+
+    if batch {
+        return Json(results).into_response();
+    }
+
+    let result = results.remove(0);
+    match result.error {
+        Some(_) => (StatusCode::UNPROCESSABLE_ENTITY, Json(result)).into_response(),
+        None => Json(result).into_response(),
+    }
+}
+
+// A transaction that couldn't be parsed, with whatever client and tx it has
+fn rejected(value: &Value, error: Error) -> Submitted {
+    let field = |name: &str| match &value[name] {
+        Value::Number(number) => Some(number.to_string()),
+        Value::String(text) => Some(text.trim().to_string()),
+        _ => None,
+    };
+
+    Submitted {
+        client: field("client").and_then(|id| id.parse().ok()),
+        tx: field("tx").and_then(|id| id.parse().ok()),
+        status: "rejected",
+        error: Some(format!("{:?}", error)),
+        account: None,
+    }
+}
+
+async fn get_account(State(state): State<Arc<AppState>>, Path(client): Path<ClientID>) -> Response {
+    match state.ledger.balances(client).await {
+        Some(balances) => Json(balances).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn get_accounts(State(state): State<Arc<AppState>>) -> Json<Vec<SerializableClient>> {
+    Json(state.ledger.accounts().await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::oneshot,
+    };
+
+    async fn request(addr: &str, method: &str, path: &str, body: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            addr,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+
+        (status, serde_json::from_str(body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let ledger = Arc::new(Ledger::new());
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, Arc::clone(&ledger), 2, async {
+            stopped.await.ok();
+        }));
+
+        let (status, body) = request(
+            &addr,
+            "POST",
+            "/transactions",
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 10}"#,
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(body["status"], "applied");
+        assert_eq!(body["account"]["available"], "10");
+
+        // A batch keeps its order: the dispute comes after the deposit it disputes
+        let (status, body) = request(
+            &addr,
+            "POST",
+            "/transactions",
+            r#"[
+                {"type": "deposit", "client": 2, "tx": 2, "amount": "5.5"},
+                {"type": "dispute", "client": 2, "tx": 2},
+                {"type": "withdrawal", "client": 1, "tx": 3, "amount": "11"}
+            ]"#,
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(body[1]["status"], "applied");
+        assert_eq!(body[1]["account"]["held"], "5.5");
+        assert_eq!(body[2]["status"], "rejected");
+        assert_eq!(body[2]["error"], "NoAvailableFunds");
+
+        let (status, body) = request(
+            &addr,
+            "POST",
+            "/transactions",
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 1}"#,
+        )
+        .await;
+        assert_eq!(
+            (status, body["error"].as_str()),
+            (422, Some("DuplicateTransaction"))
+        );
+
+        // Only what can't be parsed in a batch is rejected
+        let (status, body) = request(
+            &addr,
+            "POST",
+            "/transactions",
+            r#"[{"type": "deposit", "client": 3, "tx": 4, "amount": 1}, {"type": "deposit", "client": "4"}]"#,
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(body[0]["status"], "applied");
+        assert_eq!(
+            body[1],
+            json!({"client": 4, "status": "rejected", "error": "InvalidRecord"})
+        );
+
+        let (status, body) = request(&addr, "GET", "/accounts/2", "").await;
+        assert_eq!(status, 200);
+        assert_eq!(body[0]["total"], "5.5");
+        assert_eq!(request(&addr, "GET", "/accounts/4", "").await.0, 404);
+
+        let (status, body) = request(&addr, "GET", "/accounts", "").await;
+        assert_eq!(status, 200);
+        assert_eq!(body.as_array().unwrap().len(), 3);
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert_eq!(ledger.accounts().await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_serve_strict() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let ledger = Arc::new(Ledger::new().with_strict(true));
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, Arc::clone(&ledger), 2, async {
+            stopped.await.ok();
+        }));
+
+        // Nothing in a batch is applied if any of it can't be parsed
+        let (status, body) = request(
+            &addr,
+            "POST",
+            "/transactions",
+            r#"[{"type": "deposit", "client": 3, "tx": 4, "amount": 1}, {"type": "deposit"}]"#,
+        )
+        .await;
+        assert_eq!(status, 400);
+        assert_eq!(body, json!({"error": "InvalidRecord", "index": 1}));
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert!(ledger.accounts().await.is_empty());
+    }
+}
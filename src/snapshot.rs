@@ -0,0 +1,166 @@
+use std::io::{Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::{ClientID, ClientState},
+    error::Error,
+    transaction::TransactionID,
+};
+
+/// Bumped whenever a change to the snapshot can't be read by older versions.
+/// 3 added the admin history, fees and journal of clients, 4 replaced the journal
+/// with what it adds up to, and 5 added transfers to the journal of their recipient.
+pub const SNAPSHOT_VERSION: u32 = 5;
+
+/// Oldest version that can still be read: what was added since has defaults.
+pub const OLDEST_SNAPSHOT_VERSION: u32 = 2;
+
+/// Complete ledger state, so that batches can be chained across runs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub version: u32,
+    pub clients: Vec<ClientState>,
+    pub tx_index: Vec<(TransactionID, ClientID)>,
+    /// Last write-ahead log entry the state includes (0 if none), so recovering
+    /// from the log along with this snapshot doesn't apply them twice.
+    #[serde(default)]
+    pub wal_seq: u64,
+}
+
+// Read before the rest of the snapshot, whose layout depends on the version
+#[derive(Deserialize)]
+struct SnapshotHeader {
+    version: u32,
+}
+
+impl Snapshot {
+    pub fn new(clients: Vec<ClientState>, tx_index: Vec<(TransactionID, ClientID)>) -> Snapshot {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            clients,
+            tx_index,
+            wal_seq: 0,
+        }
+    }
+
+    pub fn with_wal_seq(mut self, seq: u64) -> Snapshot {
+        self.wal_seq = seq;
+        self
+    }
+
+    pub fn write_to<W: Write>(&self, mut out: W) -> Result<(), Error> {
+        serde_json::to_writer(&mut out, self).map_err(|_| Error::FailedWriting)?;
+        out.flush().map_err(|_| Error::FailedWriting)
+    }
+
+    pub fn read_from<R: Read>(mut input: R) -> Result<Snapshot, Error> {
+        let mut buffer = vec![];
+        input
+            .read_to_end(&mut buffer)
+            .map_err(|_| Error::InvalidSnapshot)?;
+
+        let header: SnapshotHeader =
+            serde_json::from_slice(&buffer).map_err(|_| Error::InvalidSnapshot)?;
+        if !(OLDEST_SNAPSHOT_VERSION..=SNAPSHOT_VERSION).contains(&header.version) {
+            return Err(Error::UnsupportedSnapshotVersion);
+        }
+
+        serde_json::from_slice(&buffer).map_err(|_| Error::InvalidSnapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ledger::Ledger,
+        transaction::{ParsedTransaction, TransactionType, NO_CURRENCY},
+    };
+    use rust_decimal::Decimal;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn test_snapshot_roundtrip() {
+        let ledger = Ledger::new();
+        for tx in [
+            ParsedTransaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Decimal::from_str("10.1234").ok(),
+            ),
+            ParsedTransaction::new(TransactionType::Deposit, 1, 2, Decimal::from_str("5").ok()),
+            ParsedTransaction::new(TransactionType::Deposit, 2, 3, Decimal::from_str("1").ok()),
+            ParsedTransaction::new(TransactionType::Dispute, 1, 1, Decimal::from_str("4").ok()),
+        ] {
+            ledger.apply(tx).await.unwrap();
+        }
+
+        let mut out = vec![];
+        ledger.snapshot().await.write_to(&mut out).unwrap();
+        let restored = Ledger::from_snapshot(Snapshot::read_from(out.as_slice()).unwrap());
+
+        assert_eq!(restored.accounts().await, ledger.accounts().await);
+
+        // Disputes, seen ids and the transactions index survive the snapshot
+        restored
+            .apply(ParsedTransaction::new(
+                TransactionType::Chargeback,
+                1,
+                1,
+                None,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            restored
+                .apply(ParsedTransaction::new(
+                    TransactionType::Deposit,
+                    2,
+                    2,
+                    Decimal::from_str("1").ok()
+                ))
+                .await,
+            Err(Error::DuplicateTransaction)
+        );
+        assert_eq!(
+            restored
+                .apply(ParsedTransaction::new(
+                    TransactionType::Deposit,
+                    2,
+                    3,
+                    Decimal::from_str("1").ok()
+                ))
+                .await,
+            Err(Error::DuplicateTransaction)
+        );
+
+        let account = restored.account(1, NO_CURRENCY).await.unwrap();
+        assert_eq!(account.available, Decimal::from_str("11.1234").unwrap());
+        assert_eq!(account.held, Decimal::from(0));
+        assert!(account.locked);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_version() {
+        for version in [1, 999] {
+            let snapshot = format!(r#"{{"version": {}, "clients": "unknown layout"}}"#, version);
+            assert_eq!(
+                Snapshot::read_from(snapshot.as_bytes()).unwrap_err(),
+                Error::UnsupportedSnapshotVersion
+            );
+        }
+
+        // Older snapshots without fees or history
+        let snapshot = r#"{"version": 2, "clients": [{"id": 1, "locked": false,
+            "insertion_order": 0, "balances": {}, "deposits": {}, "withdrawals": {},
+            "disputes": {}, "seen_transaction_ids": []}], "tx_index": []}"#;
+        let snapshot = Snapshot::read_from(snapshot.as_bytes()).unwrap();
+        assert_eq!((snapshot.version, snapshot.clients.len()), (2, 1));
+        assert_eq!(
+            Snapshot::read_from("not a snapshot".as_bytes()).unwrap_err(),
+            Error::InvalidSnapshot
+        );
+    }
+}
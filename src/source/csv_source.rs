@@ -0,0 +1,167 @@
+use std::{
+    io::{self, Read},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    client::queue::QueuedTransaction,
+    error::Error,
+    rejections::Rejection,
+    source::{RecordLocation, TransactionSource, STDIN_NAME},
+    transaction::{is_valid_amount, ParsedTransaction},
+};
+
+pub struct CsvSource<R: Read> {
+    name: Arc<str>,
+    reader: csv::Reader<Recorded<R>>,
+    input: Arc<Mutex<RawInput>>,
+    headers: csv::StringRecord,
+    raw_record: csv::StringRecord,
+}
+
+// Bytes read from the input, starting at byte `start`
+#[derive(Default)]
+struct RawInput {
+    start: u64,
+    bytes: Vec<u8>,
+}
+
+// Keeps what was read from `R`, so malformed records can be reported as they were
+//    in the input, rather than as the fields the csv reader trimmed
+struct Recorded<R> {
+    inner: R,
+    input: Arc<Mutex<RawInput>>,
+}
+
+impl<R: Read> Read for Recorded<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if let Ok(mut input) = self.input.lock() {
+            input.bytes.extend_from_slice(&buf[..read]);
+        }
+
+        Ok(read)
+    }
+}
+
+impl<R: Read> CsvSource<R> {
+    pub fn new(reader: R) -> Result<CsvSource<R>, Error> {
+        let input = Arc::new(Mutex::new(RawInput::default()));
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(Recorded {
+                inner: reader,
+                input: Arc::clone(&input),
+            });
+        let headers = reader.headers().map_err(|_| Error::InvalidRecord)?.clone();
+
+        Ok(CsvSource {
+            name: Arc::from(STDIN_NAME),
+            reader,
+            input,
+            headers,
+            raw_record: csv::StringRecord::new(),
+        })
+    }
+
+    /// Name of the source (eg. its path), used to locate malformed records.
+    pub fn with_name(mut self, name: &str) -> CsvSource<R> {
+        self.name = Arc::from(name);
+        self
+    }
+
+    fn location(&self, position: Option<&csv::Position>) -> RecordLocation {
+        RecordLocation {
+            source: self.name.to_string(),
+            line: position.map_or(0, |p| p.line()),
+            byte: position.map_or(0, |p| p.byte()),
+            record: position.map_or_else(String::new, |p| self.raw_line(p.byte())),
+        }
+    }
+
+    // The record starting at byte `start`, which the reader just read, without its newline
+    fn raw_line(&self, start: u64) -> String {
+        let end = self.reader.position().byte();
+        let input = match self.input.lock() {
+            Ok(input) => input,
+            Err(_) => return String::new(),
+        };
+
+        let range = (start.saturating_sub(input.start) as usize).min(input.bytes.len())
+            ..(end.saturating_sub(input.start) as usize).min(input.bytes.len());
+        String::from_utf8_lossy(&input.bytes[range])
+            .trim_end_matches(['\r', '\n'])
+            .to_string()
+    }
+
+    // Drops the bytes of the records before the one starting at byte `start`
+    fn discard_before(&self, start: u64) {
+        if let Ok(mut input) = self.input.lock() {
+            let discarded = (start.saturating_sub(input.start) as usize).min(input.bytes.len());
+            input.bytes.drain(..discarded);
+            input.start += discarded as u64;
+        }
+    }
+
+    fn rejection(&self, position: Option<&csv::Position>, error: Error) -> Rejection {
+        Rejection::unparsed(
+            self.location(position),
+            &self.headers,
+            &self.raw_record,
+            error,
+        )
+    }
+}
+
+impl<R: Read> TransactionSource for CsvSource<R> {
+    fn next_transaction(&mut self) -> Option<Result<QueuedTransaction, Rejection>> {
+        self.discard_before(self.reader.position().byte());
+
+        match self.reader.read_record(&mut self.raw_record) {
+            Ok(true) => (),
+            Ok(false) => return None,
+            Err(e) if e.is_io_error() => {
+                debug!("E0 {:?}", e);
+                let position = self.reader.position();
+                return Some(Err(Rejection::unreadable(RecordLocation {
+                    source: self.name.to_string(),
+                    line: position.line(),
+                    byte: position.byte(),
+                    record: String::new(),
+                })));
+            }
+            Err(e) => {
+                debug!("E0 {:?}", e);
+                return Some(Err(self.rejection(e.position(), Error::InvalidRecord)));
+            }
+        };
+
+        let position = self.raw_record.position();
+        let row = position.map_or(0, |p| p.line());
+
+        Some(match parse_record(&self.headers, &self.raw_record) {
+            Ok(tx) => Ok(QueuedTransaction::new(row, tx).with_source(&self.name)),
+            Err(e) => Err(self.rejection(position, e)),
+        })
+    }
+}
+
+/// Reads a csv record (with its fields already trimmed) into a transaction.
+/// A malformed amount is rejected as such, rather than as a malformed record.
+pub fn parse_record(
+    headers: &csv::StringRecord,
+    record: &csv::StringRecord,
+) -> Result<ParsedTransaction, Error> {
+    let amount = headers
+        .iter()
+        .position(|h| h == "amount")
+        .and_then(|i| record.get(i));
+    if !amount.is_none_or(is_valid_amount) {
+        return Err(Error::InvalidAmount);
+    }
+
+    record.deserialize(Some(headers)).map_err(|e| {
+        debug!("E1 {:?}", e);
+        Error::InvalidRecord
+    })
+}
@@ -0,0 +1,226 @@
+use std::convert::TryInto;
+use std::io::BufRead;
+use std::sync::Arc;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    client::queue::QueuedTransaction,
+    error::Error,
+    rejections::Rejection,
+    source::{RecordLocation, TransactionSource, STDIN_NAME},
+    transaction::{is_valid_amount, ParsedTransaction, TransactionType},
+};
+
+/// Newline-delimited json, one transaction object per line, with the same fields as the csv.
+pub struct JsonlSource<R: BufRead> {
+    name: Arc<str>,
+    reader: R,
+    buffer: String,
+    line: u64,
+    byte: u64,
+}
+
+impl<R: BufRead> JsonlSource<R> {
+    pub fn new(reader: R) -> JsonlSource<R> {
+        JsonlSource {
+            name: Arc::from(STDIN_NAME),
+            reader,
+            buffer: String::new(),
+            line: 0,
+            byte: 0,
+        }
+    }
+
+    /// Name of the source (eg. its path), used to locate malformed records.
+    pub fn with_name(mut self, name: &str) -> JsonlSource<R> {
+        self.name = Arc::from(name);
+        self
+    }
+
+    fn parse_line(&self, byte: u64) -> Result<QueuedTransaction, Rejection> {
+        let row = self.line;
+        let line = self.buffer.trim();
+
+        let rejection = |value: &Value, error: Error| {
+            let location = RecordLocation {
+                source: self.name.to_string(),
+                line: row,
+                byte,
+                record: line.to_string(),
+            };
+            unparsed(location, value, error)
+        };
+
+        let mut value: Value = serde_json::from_str(line).map_err(|e| {
+            debug!("E1 {:?}", e);
+            rejection(&Value::Null, Error::InvalidRecord)
+        })?;
+
+        match parse_transaction(&mut value) {
+            Ok(tx) => Ok(QueuedTransaction::new(row, tx).with_source(&self.name)),
+            Err(e) => Err(rejection(&value, e)),
+        }
+    }
+}
+
+/// Reads a json transaction object, with the same fields as the csv.
+///
+/// Amounts go through `to_four_dp`, just like csv ones: numbers are read as their string
+/// representation, and a null amount is the same as a missing one.
+pub fn parse_transaction(value: &mut Value) -> Result<ParsedTransaction, Error> {
+    if let Some(object) = value.as_object_mut() {
+        match object.get("amount") {
+            Some(Value::Number(n)) => {
+                let amount = Value::String(n.to_string());
+                object.insert("amount".to_string(), amount);
+            }
+            Some(Value::Null) => {
+                object.remove("amount");
+            }
+            Some(Value::String(amount)) if !is_valid_amount(amount) => {
+                return Err(Error::InvalidAmount);
+            }
+            _ => (),
+        }
+    }
+
+    ParsedTransaction::deserialize(&*value).map_err(|e| {
+        debug!("E1 {:?}", e);
+        Error::InvalidRecord
+    })
+}
+
+impl<R: BufRead> TransactionSource for JsonlSource<R> {
+    fn next_transaction(&mut self) -> Option<Result<QueuedTransaction, Rejection>> {
+        loop {
+            self.buffer.clear();
+            let read = match self.reader.read_line(&mut self.buffer) {
+                Ok(0) => return None,
+                Ok(read) => read,
+                Err(e) => {
+                    debug!("E0 {:?}", e);
+                    return Some(Err(Rejection::unreadable(RecordLocation {
+                        source: self.name.to_string(),
+                        line: self.line + 1,
+                        byte: self.byte,
+                        record: String::new(),
+                    })));
+                }
+            };
+
+            let byte = self.byte;
+            self.byte += read as u64;
+            self.line += 1;
+
+            if !self.buffer.trim().is_empty() {
+                return Some(self.parse_line(byte));
+            }
+        }
+    }
+}
+
+// Fields are filled in on a best-effort basis from the raw record
+fn unparsed(location: RecordLocation, value: &Value, error: Error) -> Rejection {
+    Rejection {
+        row: location.line,
+        client: value
+            .get("client")
+            .and_then(Value::as_u64)
+            .and_then(|v| v.try_into().ok()),
+        tx: value
+            .get("tx")
+            .and_then(Value::as_u64)
+            .and_then(|v| v.try_into().ok()),
+        tx_type: value
+            .get("type")
+            .and_then(Value::as_str)
+            .and_then(TransactionType::from_name),
+        error: format!("{:?}", error),
+        source: Some(location.source.clone()),
+        location: Some(Box::new(location)),
+        fatal: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn test_jsonl_source() {
+        let input = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1.23456"}
+            {"type": "deposit", "client": 1, "tx": 2, "amount": 2.5}
+
+            {"type": "dispute", "client": 1, "tx": 1}
+            {"type": "resolve", "client": 1, "tx": 1, "amount": null}
+            {"type": "withdrawal", "client": 1, "tx": 3, "amount": "a.5"}
+            {"type": "deposdit", "client": 1, "tx": 4, "amount": 1}
+            {"type": "deposit", "client": 1,"#
+            .as_bytes();
+
+        let mut source = JsonlSource::new(input);
+        let mut next = || source.next_transaction().unwrap();
+
+        let parsed = |row, tx_type, tx_id, amount: Option<&str>| {
+            Ok(QueuedTransaction::new(
+                row,
+                ParsedTransaction::new(
+                    tx_type,
+                    1,
+                    tx_id,
+                    amount.map(|a| Decimal::from_str(a).unwrap()),
+                ),
+            )
+            .with_source(&Arc::from(STDIN_NAME)))
+        };
+
+        assert_eq!(
+            next(),
+            parsed(1, TransactionType::Deposit, 1, Some("1.2346"))
+        );
+        assert_eq!(next(), parsed(2, TransactionType::Deposit, 2, Some("2.5")));
+        assert_eq!(next(), parsed(4, TransactionType::Dispute, 1, None));
+        assert_eq!(next(), parsed(5, TransactionType::Resolve, 1, None));
+        assert_eq!(next().unwrap_err().error, "InvalidAmount");
+        let rejection = next().unwrap_err();
+        assert_eq!(
+            (
+                rejection.row,
+                rejection.client,
+                rejection.tx,
+                rejection.tx_type
+            ),
+            (7, Some(1), Some(4), None)
+        );
+        assert_eq!(next().unwrap_err().row, 8);
+        assert!(source.next_transaction().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_jsonl_malformed_amount() {
+        let input = concat!(
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1.0"}"#,
+            "\n",
+            r#"{"type": "deposit", "client": 1, "tx": 2, "amount": "a.5"}"#,
+            "\n"
+        )
+        .as_bytes();
+
+        let mut source = JsonlSource::new(input).with_name("test.jsonl");
+
+        assert!(source.next_transaction().unwrap().is_ok());
+        assert_eq!(
+            source.next_transaction().unwrap().unwrap_err().into_error(),
+            Error::MalformedRecord(Box::new(RecordLocation {
+                source: "test.jsonl".to_string(),
+                line: 2,
+                byte: 59,
+                record: r#"{"type": "deposit", "client": 1, "tx": 2, "amount": "a.5"}"#.to_string(),
+            }))
+        );
+    }
+}
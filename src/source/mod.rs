@@ -0,0 +1,231 @@
+mod csv_source;
+mod jsonl_source;
+
+pub use csv_source::{parse_record, CsvSource};
+pub use jsonl_source::{parse_transaction, JsonlSource};
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+    str::FromStr,
+};
+
+use crate::{client::queue::QueuedTransaction, error::Error, rejections::Rejection};
+
+/// Input layer: any format that can be turned into a stream of `ParsedTransaction`s.
+pub trait TransactionSource {
+    /// Next transaction, along with the row it was read from.
+    /// Records that can't be parsed are returned as a `Rejection`.
+    fn next_transaction(&mut self) -> Option<Result<QueuedTransaction, Rejection>>;
+}
+
+/// Where a record was read from, and its contents.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordLocation {
+    pub source: String,
+    pub line: u64,
+    pub byte: u64,
+    pub record: String,
+}
+
+impl fmt::Display for RecordLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{} (byte {}): {:?}",
+            self.source, self.line, self.byte, self.record
+        )
+    }
+}
+
+pub const STDIN_NAME: &str = "<stdin>";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Csv,
+    Jsonl,
+}
+
+impl InputFormat {
+    /// Guesses the format from the file extension, defaulting to csv.
+    pub fn from_path(path: &str) -> InputFormat {
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("jsonl") | Some("ndjson") => InputFormat::Jsonl,
+            _ => InputFormat::Csv,
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(InputFormat::Csv),
+            "jsonl" => Ok(InputFormat::Jsonl),
+            _ => Err(Error::UnknownFormat),
+        }
+    }
+}
+
+/// Reads several sources in order, as if they were a single one.
+pub struct ChainedSource {
+    sources: VecDeque<Box<dyn TransactionSource + Send>>,
+}
+
+impl ChainedSource {
+    pub fn new(sources: Vec<Box<dyn TransactionSource + Send>>) -> ChainedSource {
+        ChainedSource {
+            sources: sources.into(),
+        }
+    }
+}
+
+impl TransactionSource for ChainedSource {
+    fn next_transaction(&mut self) -> Option<Result<QueuedTransaction, Rejection>> {
+        loop {
+            match self.sources.front_mut()?.next_transaction() {
+                Some(tx) => return Some(tx),
+                None => {
+                    self.sources.pop_front();
+                }
+            }
+        }
+    }
+}
+
+/// Opens `path` as a source of the given format, or guesses it from the extension.
+/// `-` reads from stdin.
+pub fn open(
+    path: &str,
+    format: Option<InputFormat>,
+) -> Result<Box<dyn TransactionSource + Send>, Error> {
+    let format = format.unwrap_or_else(|| InputFormat::from_path(path));
+
+    if path == "-" {
+        return match format {
+            InputFormat::Csv => Ok(Box::new(CsvSource::new(io::stdin())?)),
+            InputFormat::Jsonl => Ok(Box::new(JsonlSource::new(BufReader::new(io::stdin())))),
+        };
+    }
+
+    let file = File::open(path).map_err(|_| Error::UnknownFile)?;
+
+    match format {
+        InputFormat::Csv => Ok(Box::new(CsvSource::new(file)?.with_name(path))),
+        InputFormat::Jsonl => Ok(Box::new(
+            JsonlSource::new(BufReader::new(file)).with_name(path),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_chained_source() {
+        let first = "type,client,tx,amount\ndeposit,1,1,1.0\n".as_bytes();
+        let second = "type,client,tx,amount\ndeposit,1,2,1.0\ndeposit,2,3,1.0\n".as_bytes();
+
+        let mut source = ChainedSource::new(vec![
+            Box::new(CsvSource::new(first).unwrap()),
+            Box::new(CsvSource::new("type,client,tx,amount\n".as_bytes()).unwrap()),
+            Box::new(CsvSource::new(second).unwrap()),
+        ]);
+
+        let mut tx_ids = vec![];
+        while let Some(tx) = source.next_transaction() {
+            tx_ids.push(tx.unwrap().tx.tx_id);
+        }
+        assert_eq!(tx_ids, vec![1, 2, 3]);
+
+        // Records keep track of the input they came from
+        let mut source = ChainedSource::new(vec![
+            Box::new(CsvSource::new(first).unwrap().with_name("first.csv")),
+            Box::new(
+                CsvSource::new("type,client,tx,amount\ndeposit,1,x,1.0\n".as_bytes())
+                    .unwrap()
+                    .with_name("second.csv"),
+            ),
+        ]);
+        let tx = source.next_transaction().unwrap().unwrap();
+        assert_eq!(tx.source.as_deref(), Some("first.csv"));
+        let rejection = source.next_transaction().unwrap().unwrap_err();
+        assert_eq!(rejection.source.as_deref(), Some("second.csv"));
+    }
+
+    // Reads `data`, then fails
+    struct FailingReader<'a>(&'a [u8]);
+
+    impl io::Read for FailingReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(io::Error::other("disk failure")),
+                read => Ok(read),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_error() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+
+        let mut source = CsvSource::new(FailingReader(input.as_bytes())).unwrap();
+        assert!(source.next_transaction().unwrap().is_ok());
+        let rejection = source.next_transaction().unwrap().unwrap_err();
+        assert!(rejection.fatal);
+        assert!(matches!(rejection.into_error(), Error::FailedReading(_)));
+
+        let mut source = JsonlSource::new(BufReader::new(FailingReader(
+            br#"{"type": "deposit", "client": 1, "tx": 1, "amount": 1}
+"#,
+        )));
+        assert!(source.next_transaction().unwrap().is_ok());
+        assert!(source.next_transaction().unwrap().unwrap_err().fatal);
+
+        // The input is cut short, even when not in strict mode
+        let ledger = crate::ledger::Ledger::new();
+        let source = CsvSource::new(FailingReader(input.as_bytes())).unwrap();
+        assert!(matches!(
+            ledger.process_source(Box::new(source), 2).await,
+            Err(Error::FailedReading(_))
+        ));
+        let source = CsvSource::new(FailingReader(input.as_bytes())).unwrap();
+        assert!(matches!(
+            ledger.replay_source(Box::new(source)).await,
+            Err(Error::FailedReading(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_csv_malformed_amount() {
+        let input = "type,client,tx,amount\ndeposit,1,1,\ndeposit, 1, 2, a.5\n\"deposit\" ,1\r\ndeposit,1,3,1\n"
+            .as_bytes();
+
+        let mut source = CsvSource::new(input).unwrap().with_name("test.csv");
+
+        assert!(source.next_transaction().unwrap().is_ok());
+        assert_eq!(
+            source.next_transaction().unwrap().unwrap_err().into_error(),
+            Error::MalformedRecord(Box::new(RecordLocation {
+                source: "test.csv".to_string(),
+                line: 3,
+                byte: 35,
+                record: "deposit, 1, 2, a.5".to_string(),
+            }))
+        );
+
+        // Rejections show the record as it was in the input
+        let rejection = source.next_transaction().unwrap().unwrap_err();
+        assert_eq!(
+            rejection.location.unwrap().record,
+            "\"deposit\" ,1".to_string()
+        );
+        assert!(source.next_transaction().unwrap().is_ok());
+        assert!(source.next_transaction().is_none());
+    }
+}
@@ -1,16 +1,35 @@
-use crate::client::{Client, ClientID};
+use crate::client::ClientID;
 use crate::error::Error;
+use crate::rates::Timestamp;
 
 use rust_decimal::Decimal;
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::{BTreeMap, HashSet};
 use std::str::FromStr;
 
 pub type TransactionID = u32;
+/// Currency code, eg. `EUR`. Transactions without one use `NO_CURRENCY`.
+pub type Currency = String;
+pub const NO_CURRENCY: &str = "";
 pub type TransactionsMap = BTreeMap<TransactionID, Transaction>;
 pub type UniqueTransactionIDs = HashSet<TransactionID>;
+pub type DisputesMap = BTreeMap<TransactionID, DisputeState>;
 
-#[derive(Deserialize, Debug, Clone, PartialEq)]
+/// How much of a transaction is currently under dispute, and how much of it
+/// has already been charged back.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct DisputeState {
+    pub disputed: Decimal,
+    pub charged_back: Decimal,
+}
+
+impl DisputeState {
+    pub fn is_empty(&self) -> bool {
+        self.disputed.is_zero() && self.charged_back.is_zero()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum TransactionType {
     #[serde(rename = "deposit")]
     Deposit,
@@ -26,9 +45,61 @@ pub enum TransactionType {
 
     #[serde(rename = "chargeback")]
     Chargeback,
+
+    // Administrative transactions, which lock/unlock the client
+    #[serde(rename = "freeze")]
+    Freeze,
+
+    #[serde(rename = "unlock")]
+    Unlock,
+
+    // Moves funds of a client from one currency to another
+    #[serde(rename = "convert")]
+    Convert,
+
+    // Moves funds from one client to another
+    #[serde(rename = "transfer")]
+    Transfer,
+
+    // Charges the client a fee, which goes to the house
+    #[serde(rename = "fee")]
+    Fee,
 }
 
-#[derive(Deserialize, Debug, Clone, PartialEq)]
+impl TransactionType {
+    pub fn from_name(name: &str) -> Option<TransactionType> {
+        match name {
+            "deposit" => Some(TransactionType::Deposit),
+            "withdrawal" => Some(TransactionType::Withdrawal),
+            "dispute" => Some(TransactionType::Dispute),
+            "resolve" => Some(TransactionType::Resolve),
+            "chargeback" => Some(TransactionType::Chargeback),
+            "freeze" => Some(TransactionType::Freeze),
+            "unlock" => Some(TransactionType::Unlock),
+            "convert" => Some(TransactionType::Convert),
+            "transfer" => Some(TransactionType::Transfer),
+            "fee" => Some(TransactionType::Fee),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Freeze => "freeze",
+            TransactionType::Unlock => "unlock",
+            TransactionType::Convert => "convert",
+            TransactionType::Transfer => "transfer",
+            TransactionType::Fee => "fee",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct ParsedTransaction {
     #[serde(rename = "type")]
     pub tx_type: TransactionType,
@@ -36,39 +107,130 @@ pub struct ParsedTransaction {
     pub client_id: ClientID,
     #[serde(rename = "tx")]
     pub tx_id: u32,
-    #[serde(deserialize_with = "to_four_dp")]
+    #[serde(
+        default,
+        deserialize_with = "to_four_dp",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub amount: Option<Decimal>,
+    /// Reason code of an administrative transaction
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "to_currency",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub currency: Option<Currency>,
+    /// Currency a conversion credits
+    #[serde(
+        default,
+        deserialize_with = "to_currency",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub to_currency: Option<Currency>,
+    /// When the transaction happened, which picks the rate of a conversion
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+    /// Client a transfer credits (`client` being the one it debits)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<ClientID>,
+    /// Rate a conversion is made at, looked up from its `timestamp` when it's applied
+    /// (and logged with it). Any rate in the input is ignored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate: Option<Decimal>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
     pub tx_type: TransactionType,
     pub amount: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to_currency: Option<Currency>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate: Option<Decimal>,
+}
+
+/// A freeze/unlock applied to a client, or a chargeback that locked it, kept so locked
+/// accounts can be reviewed. Chargebacks have the id of the transaction they charged back,
+/// and no reason.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AdminAction {
+    pub tx: TransactionID,
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    pub reason: String,
 }
 
 impl ParsedTransaction {
+    /// A transaction with only the fields every type has, and no currency.
+    pub fn new(
+        tx_type: TransactionType,
+        client_id: ClientID,
+        tx_id: TransactionID,
+        amount: Option<Decimal>,
+    ) -> ParsedTransaction {
+        ParsedTransaction {
+            tx_type,
+            client_id,
+            tx_id,
+            amount,
+            reason: None,
+            currency: None,
+            to_currency: None,
+            timestamp: None,
+            to: None,
+            rate: None,
+        }
+    }
+
     pub fn extract_tx(self) -> Transaction {
         Transaction {
             tx_type: self.tx_type,
             amount: self.amount,
+            reason: self.reason,
+            currency: self.currency,
+            to_currency: self.to_currency,
+            timestamp: self.timestamp,
+            rate: self.rate,
         }
     }
-
-    pub fn extract_tx_and_client(self) -> (Transaction, Client) {
-        (
-            Transaction {
-                tx_type: self.tx_type,
-                amount: self.amount,
-            },
-            Client::new(self.client_id),
-        )
-    }
 }
 
 impl Transaction {
+    /// Same as `ParsedTransaction::new`.
+    pub fn new(tx_type: TransactionType, amount: Option<Decimal>) -> Transaction {
+        Transaction {
+            tx_type,
+            amount,
+            reason: None,
+            currency: None,
+            to_currency: None,
+            timestamp: None,
+            rate: None,
+        }
+    }
+
     pub fn get_amount(&self) -> Result<Decimal, Error> {
         self.amount.ok_or(Error::InvalidAmount)
     }
+
+    pub fn currency(&self) -> &str {
+        self.currency.as_deref().unwrap_or(NO_CURRENCY)
+    }
+}
+
+/// Whether `to_four_dp` can read `amount`: an empty amount is a missing one, anything
+/// else must be a number.
+pub fn is_valid_amount(amount: &str) -> bool {
+    let amount = amount.trim();
+    amount.is_empty() || Decimal::from_str(amount).is_ok()
 }
 
 fn to_four_dp<'de, D>(deserializer: D) -> Result<Option<Decimal>, D::Error>
@@ -76,10 +238,33 @@ where
     D: Deserializer<'de>,
 {
     let s: &str = Deserialize::deserialize(deserializer)?;
-    match Decimal::from_str(s) {
-        Ok(val) => Ok(Some(val.round_dp(4))),
-        Err(_) => Ok(None),
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(None);
     }
+
+    Decimal::from_str(s)
+        .map(|val| Some(val.round_dp(4)))
+        .map_err(serde::de::Error::custom)
+}
+
+// Currency codes are case insensitive: an empty code is a missing one
+fn to_currency<'de, D>(deserializer: D) -> Result<Option<Currency>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let currency: Option<String> = Deserialize::deserialize(deserializer)?;
+    Ok(currency
+        .map(|currency| currency.trim().to_uppercase())
+        .filter(|currency| !currency.is_empty()))
+}
+
+/// Same as `to_currency`, for codes that can't be missing.
+pub(crate) fn to_currency_code<'de, D>(deserializer: D) -> Result<Currency, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    to_currency(deserializer)?.ok_or_else(|| serde::de::Error::custom("missing currency"))
 }
 
 #[cfg(test)]
@@ -111,42 +296,26 @@ mod tests {
             .from_reader(tx);
 
         let valid_transactions = &[
-            ParsedTransaction {
-                amount: Some(Decimal::from_str("37.4444").unwrap()),
-                client_id: 30283,
-                tx_id: 3032270210,
-                tx_type: TransactionType::Deposit,
-            },
-            ParsedTransaction {
-                amount: None,
-                client_id: 30283,
-                tx_id: 3032270210,
-                tx_type: TransactionType::Chargeback,
-            },
-            ParsedTransaction {
-                amount: None,
-                client_id: 30283,
-                tx_id: 3032270210,
-                tx_type: TransactionType::Resolve,
-            },
-            ParsedTransaction {
-                amount: Some(Decimal::from_str("37.4444").unwrap()),
-                client_id: 30283,
-                tx_id: 3032270210,
-                tx_type: TransactionType::Dispute,
-            },
-            ParsedTransaction {
-                amount: Some(Decimal::from_str("37.4444").unwrap()),
-                client_id: 30283,
-                tx_id: 3032270210,
-                tx_type: TransactionType::Withdrawal,
-            },
-            ParsedTransaction {
-                amount: None,
-                client_id: 2,
-                tx_id: 1,
-                tx_type: TransactionType::Deposit,
-            },
+            ParsedTransaction::new(
+                TransactionType::Deposit,
+                30283,
+                3032270210,
+                Some(Decimal::from_str("37.4444").unwrap()),
+            ),
+            ParsedTransaction::new(TransactionType::Chargeback, 30283, 3032270210, None),
+            ParsedTransaction::new(TransactionType::Resolve, 30283, 3032270210, None),
+            ParsedTransaction::new(
+                TransactionType::Dispute,
+                30283,
+                3032270210,
+                Some(Decimal::from_str("37.4444").unwrap()),
+            ),
+            ParsedTransaction::new(
+                TransactionType::Withdrawal,
+                30283,
+                3032270210,
+                Some(Decimal::from_str("37.4444").unwrap()),
+            ),
         ];
 
         let mut iter_der = reader.deserialize();
@@ -165,6 +334,24 @@ mod tests {
         assert!(iter_der.next().unwrap().is_err());
         assert!(iter_der.next().unwrap().is_err());
         assert!(iter_der.next().unwrap().is_err());
-        assert_eq!(valid_transactions[5], iter_der.next().unwrap().unwrap());
+        // A malformed amount isn't read as a missing one
+        assert!(iter_der.next().unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_deserialize_currency() {
+        let tx = "type,client,tx,amount,currency
+        deposit,1,1,1.5, eur
+        deposit,1,2,1.5,";
+
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(tx.as_bytes());
+        let currencies = reader
+            .deserialize()
+            .map(|tx: Result<ParsedTransaction, csv::Error>| tx.unwrap().currency)
+            .collect::<Vec<_>>();
+
+        assert_eq!(currencies, vec![Some("EUR".to_string()), None]);
     }
 }
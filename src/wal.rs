@@ -0,0 +1,364 @@
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    client::queue::QueuedTransaction, error::Error, rejections::Rejection,
+    source::TransactionSource, transaction::ParsedTransaction,
+};
+
+/// A transaction that was about to be applied.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    /// Number of the entry, increasing across runs, so a snapshot can tell which
+    /// entries it already includes.
+    #[serde(default)]
+    pub seq: u64,
+    /// Position of the record in the input, if it was read from one.
+    pub offset: Option<u64>,
+    pub tx: ParsedTransaction,
+    /// Whether it was rejected before it could be applied (eg. its id was already used),
+    /// in which case recovering only skips its record.
+    #[serde(default)]
+    pub rejected: bool,
+}
+
+/// Append-only log of every transaction, written before it changes any client.
+///
+/// One json entry per line. A crash while appending can only leave a partial last line,
+/// which is dropped when the log is opened again.
+pub struct WriteAheadLog {
+    file: Mutex<LogFile>,
+}
+
+struct LogFile {
+    file: File,
+    // Number of the last entry appended (or recovered)
+    last_seq: u64,
+}
+
+impl WriteAheadLog {
+    /// Opens (or creates) the log at `path`, returning the entries already in it.
+    pub fn open(path: &str) -> Result<(WriteAheadLog, Vec<LogEntry>), Error> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(|_| Error::UnknownFile)?;
+
+        let mut contents = vec![];
+        file.read_to_end(&mut contents)
+            .map_err(|_| Error::InvalidLog)?;
+
+        let complete = contents
+            .iter()
+            .rposition(|b| *b == b'\n')
+            .map_or(0, |i| i + 1);
+        if complete < contents.len() {
+            warn!("Dropping a partial entry at the end of the write-ahead log");
+            file.set_len(complete as u64)
+                .map_err(|_| Error::FailedWritingLog)?;
+        }
+
+        let entries = contents[..complete]
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).map_err(|_| Error::InvalidLog))
+            .collect::<Result<Vec<LogEntry>, Error>>()?;
+
+        let wal = WriteAheadLog {
+            file: Mutex::new(LogFile {
+                file,
+                last_seq: entries.last().map_or(0, |entry| entry.seq),
+            }),
+        };
+        Ok((wal, entries))
+    }
+
+    /// Numbers the next entries after `seq` (eg. the last entry a snapshot includes),
+    /// unless the log is already past it.
+    pub async fn continue_after(&self, seq: u64) {
+        let mut log = self.file.lock().await;
+        log.last_seq = log.last_seq.max(seq);
+    }
+
+    /// Number of the last entry appended, or 0 if there's none.
+    pub async fn last_seq(&self) -> u64 {
+        self.file.lock().await.last_seq
+    }
+
+    pub async fn append(&self, offset: Option<u64>, tx: &ParsedTransaction) -> Result<(), Error> {
+        self.write_entry(offset, tx, false).await
+    }
+
+    /// Logs that `tx` was rejected before it was appended, so its record isn't checked
+    /// again when recovering, against a different state.
+    pub async fn append_rejected(
+        &self,
+        offset: Option<u64>,
+        tx: &ParsedTransaction,
+    ) -> Result<(), Error> {
+        self.write_entry(offset, tx, true).await
+    }
+
+    async fn write_entry(
+        &self,
+        offset: Option<u64>,
+        tx: &ParsedTransaction,
+        rejected: bool,
+    ) -> Result<(), Error> {
+        let mut log = self.file.lock().await;
+        let seq = log.last_seq + 1;
+
+        let mut line = serde_json::to_vec(&LogEntry {
+            seq,
+            offset,
+            tx: tx.clone(),
+            rejected,
+        })
+        .map_err(|_| Error::FailedWritingLog)?;
+        line.push(b'\n');
+
+        // A single unbuffered write, synced: once it returns, the entry survives a crash
+        log.file
+            .write_all(&line)
+            .and_then(|_| log.file.sync_data())
+            .map_err(|_| Error::FailedWritingLog)?;
+        log.last_seq = seq;
+
+        Ok(())
+    }
+
+    /// Empties the log, once the run it logged finished. Entries keep being numbered
+    /// from where they were.
+    pub async fn truncate(&self) -> Result<(), Error> {
+        let log = self.file.lock().await;
+        log.file
+            .set_len(0)
+            .and_then(|_| log.file.sync_data())
+            .map_err(|_| Error::FailedWritingLog)
+    }
+}
+
+/// Numbers every record of `source` with its offset, skipping the ones already applied
+/// according to the write-ahead log.
+pub struct ResumedSource {
+    source: Box<dyn TransactionSource + Send>,
+    offset: u64,
+    applied: HashSet<u64>,
+}
+
+impl ResumedSource {
+    pub fn new(source: Box<dyn TransactionSource + Send>, applied: HashSet<u64>) -> ResumedSource {
+        ResumedSource {
+            source,
+            offset: 0,
+            applied,
+        }
+    }
+}
+
+impl TransactionSource for ResumedSource {
+    fn next_transaction(&mut self) -> Option<Result<QueuedTransaction, Rejection>> {
+        loop {
+            let tx = self.source.next_transaction()?;
+            let offset = self.offset;
+            self.offset += 1;
+
+            if self.applied.contains(&offset) {
+                continue;
+            }
+
+            return Some(tx.map(|tx| QueuedTransaction {
+                offset: Some(offset),
+                ..tx
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ledger::Ledger, rates::RateTable, source::CsvSource, transaction::NO_CURRENCY};
+    use rust_decimal::Decimal;
+
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,5
+withdrawal,1,3,4
+dispute,2,2,
+deposit,1,4,1
+";
+
+    fn source() -> Box<dyn TransactionSource + Send> {
+        Box::new(CsvSource::new(INPUT.as_bytes()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_recovery() {
+        let path = std::env::temp_dir().join(format!("pay-test-{}.wal", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        // Simulate a crash after the first three records, in the middle of writing the fourth
+        {
+            let (wal, entries) = WriteAheadLog::open(path).unwrap();
+            assert!(entries.is_empty());
+
+            let mut source = source();
+            for offset in 0..3 {
+                let tx = source.next_transaction().unwrap().unwrap().tx;
+                wal.append(Some(offset), &tx).await.unwrap();
+            }
+            wal.file
+                .lock()
+                .await
+                .file
+                .write_all(br#"{"seq":4,"offset":3,"tx":{"ty"#)
+                .unwrap();
+        }
+
+        let ledger = Ledger::new().with_wal(path).await.unwrap();
+        assert_eq!(
+            ledger
+                .account(1, NO_CURRENCY)
+                .await
+                .unwrap()
+                .available
+                .to_string(),
+            "6"
+        );
+        ledger.process_source(source(), 2).await.unwrap();
+
+        let expected = Ledger::new();
+        expected.replay_source(source()).await.unwrap();
+        assert_eq!(ledger.accounts().await, expected.accounts().await);
+
+        // Everything is in the log now: recovering again resumes past the end of the input
+        let (_, entries) = WriteAheadLog::open(path).unwrap();
+        assert_eq!(entries.len(), 5);
+        let ledger = Ledger::new().with_wal(path).await.unwrap();
+        ledger.process_source(source(), 2).await.unwrap();
+        assert_eq!(ledger.accounts().await, expected.accounts().await);
+
+        // Simulate a crash right after saving the state: its entries aren't applied again
+        let snapshot = ledger.snapshot().await;
+        assert_eq!(snapshot.wal_seq, 5);
+        let ledger = Ledger::from_snapshot(snapshot.clone())
+            .with_wal(path)
+            .await
+            .unwrap();
+        assert_eq!(ledger.accounts().await, expected.accounts().await);
+
+        // Once the run finishes, the log is emptied, and the next input is processed whole
+        ledger.finish().await.unwrap();
+        let (_, entries) = WriteAheadLog::open(path).unwrap();
+        assert!(entries.is_empty());
+        let ledger = Ledger::from_snapshot(snapshot)
+            .with_wal(path)
+            .await
+            .unwrap();
+        let next = "type,client,tx,amount\ndeposit,1,10,1\n";
+        ledger
+            .process_source(Box::new(CsvSource::new(next.as_bytes()).unwrap()), 2)
+            .await
+            .unwrap();
+        let (_, entries) = WriteAheadLog::open(path).unwrap();
+        assert_eq!(entries.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![6]);
+        assert_eq!(
+            ledger.account(1, NO_CURRENCY).await.unwrap().available,
+            expected.account(1, NO_CURRENCY).await.unwrap().available + Decimal::ONE
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recovery_of_conversions() {
+        let path =
+            std::env::temp_dir().join(format!("pay-test-{}-convert.wal", std::process::id()));
+        let path = path.to_str().unwrap();
+        let input = "type,client,tx,amount,currency,to_currency,timestamp
+deposit,1,1,10,EUR,,
+convert,1,2,4,EUR,USD,100
+";
+        let rates = |rate: u32| RateTable::new().with_rate("EUR", "USD", rate.into(), 0);
+
+        let ledger = Ledger::new()
+            .with_rates(rates(2))
+            .with_wal(path)
+            .await
+            .unwrap();
+        ledger
+            .process_source(Box::new(CsvSource::new(input.as_bytes()).unwrap()), 2)
+            .await
+            .unwrap();
+        drop(ledger);
+
+        // Made at the rate of the first run, whatever the rates when recovering
+        for ledger in [Ledger::new().with_rates(rates(3)), Ledger::new()] {
+            let ledger = ledger.with_wal(path).await.unwrap();
+            assert_eq!(
+                ledger.account(1, "USD").await.unwrap().available,
+                Decimal::from(8)
+            );
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recovery_of_rejections() {
+        let path =
+            std::env::temp_dir().join(format!("pay-test-{}-rejected.wal", std::process::id()));
+        let path = path.to_str().unwrap();
+        let input = |csv: &'static str| -> Box<dyn TransactionSource + Send> {
+            Box::new(CsvSource::new(csv.as_bytes()).unwrap())
+        };
+
+        // Rejected before being applied, as the id is already used
+        let ledger = Ledger::new().with_wal(path).await.unwrap();
+        ledger
+            .process_source(
+                input("type,client,tx,amount\ndeposit,1,1,10\ndeposit,2,1,5\n"),
+                2,
+            )
+            .await
+            .unwrap();
+        let (_, entries) = WriteAheadLog::open(path).unwrap();
+        assert_eq!(
+            entries.iter().map(|e| e.rejected).collect::<Vec<_>>(),
+            vec![false, true]
+        );
+        drop(ledger);
+
+        // The id was free again by the time of the crash (the first run applied the
+        //    records of both clients in a different order): the record is still skipped
+        std::fs::remove_file(path).unwrap();
+        {
+            let (wal, _) = WriteAheadLog::open(path).unwrap();
+            let mut source = input("type,client,tx,amount\nwithdrawal,1,1,10\ndeposit,2,1,5\n");
+            let withdrawal = source.next_transaction().unwrap().unwrap().tx;
+            let deposit = source.next_transaction().unwrap().unwrap().tx;
+            wal.append(Some(0), &withdrawal).await.unwrap();
+            wal.append_rejected(Some(1), &deposit).await.unwrap();
+        }
+        let ledger = Ledger::new().with_wal(path).await.unwrap();
+        ledger
+            .process_source(
+                input("type,client,tx,amount\nwithdrawal,1,1,10\ndeposit,2,1,5\n"),
+                2,
+            )
+            .await
+            .unwrap();
+        assert_eq!(ledger.account(2, NO_CURRENCY).await, None);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
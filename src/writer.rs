@@ -1,14 +1,400 @@
-use crate::client::db::ClientsDB;
-use std::{io, sync::Arc};
+use crate::{
+    client::{storage::Storage, ClientID, FeesMap, SerializableClient},
+    error::Error,
+    transaction::{Currency, NO_CURRENCY},
+};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::{
+    io::{self, Write},
+    str::FromStr,
+    sync::Arc,
+};
 
-pub async fn write(db: Arc<ClientsDB>) {
-    let mut writer = csv::Writer::from_writer(io::stdout());
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputOrder {
+    #[default]
+    ClientID,
+    // Order in which clients were first added to the DB
+    Insertion,
+}
+
+impl FromStr for OutputOrder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(OutputOrder::ClientID),
+            "insertion" => Ok(OutputOrder::Insertion),
+            _ => Err(Error::UnknownFormat),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    // A single json array with all clients
+    Json,
+    // One json object per client, per line
+    Jsonl,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::Jsonl),
+            _ => Err(Error::UnknownFormat),
+        }
+    }
+}
+
+pub async fn write(
+    storage: Arc<dyn Storage>,
+    order: OutputOrder,
+    format: OutputFormat,
+) -> Result<(), Error> {
+    write_to(storage, order, format, io::stdout()).await
+}
+
+/// Writes all clients. If no transaction had a currency, there's no `currency` column.
+pub async fn write_to<W: io::Write>(
+    storage: Arc<dyn Storage>,
+    order: OutputOrder,
+    format: OutputFormat,
+    out: W,
+) -> Result<(), Error> {
+    let clients = sorted_clients(storage, order).await;
+    let with_currency = clients.iter().any(|client| client.currency != NO_CURRENCY);
+
+    let rows = clients.into_iter().map(|client| OutputRow {
+        client: client.client,
+        currency: with_currency.then_some(client.currency),
+        available: client.available,
+        held: client.held,
+        total: client.total,
+        locked: client.locked,
+    });
+
+    write_rows(rows, format, out)
+}
+
+// A row of the output, leaving `currency` out as before currencies existed
+#[derive(Serialize)]
+struct OutputRow {
+    client: ClientID,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+}
+
+/// Fees the house collected in one currency.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Revenue {
+    pub currency: Currency,
+    pub revenue: Decimal,
+}
+
+/// Writes the fees the house collected, one row per currency, sorted by currency.
+pub async fn write_revenue<W: io::Write>(
+    storage: Arc<dyn Storage>,
+    format: OutputFormat,
+    out: W,
+) -> Result<(), Error> {
+    let revenue = house_revenue(&*storage)
+        .await
+        .into_iter()
+        .map(|(currency, revenue)| Revenue {
+            currency,
+            revenue: revenue.round_dp(4),
+        });
+
+    write_rows(revenue, format, out)
+}
+
+/// Fees paid by all clients, by currency.
+pub async fn house_revenue(storage: &dyn Storage) -> FeesMap {
+    let mut revenue = FeesMap::new();
+    storage
+        .visit_clients(&mut |client| {
+            for (currency, fees) in client.fees() {
+                *revenue.entry(currency.clone()).or_default() += *fees;
+            }
+        })
+        .await;
+
+    revenue
+}
+
+/// A client whose available funds went negative, eg. by disputing a deposit that
+/// was already withdrawn (with `DisputePolicy::Full`).
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Deficit {
+    pub client: ClientID,
+    /// Left out if no transaction had a currency, like in the output
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    /// What the client owes: how far below zero `available` is
+    pub deficit: Decimal,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+/// Writes the clients in deficit, sorted by client id.
+pub async fn write_deficits<W: io::Write>(
+    storage: Arc<dyn Storage>,
+    format: OutputFormat,
+    out: W,
+) -> Result<(), Error> {
+    let clients = sorted_clients(storage, OutputOrder::ClientID).await;
+    let with_currency = clients.iter().any(|client| client.currency != NO_CURRENCY);
+
+    let deficits = clients
+        .into_iter()
+        .filter(|client| client.available < Decimal::ZERO)
+        .map(|client| Deficit {
+            client: client.client,
+            currency: with_currency.then_some(client.currency),
+            deficit: -client.available,
+            available: client.available,
+            held: client.held,
+            total: client.total,
+            locked: client.locked,
+        });
+
+    write_rows(deficits, format, out)
+}
+
+async fn sorted_clients(storage: Arc<dyn Storage>, order: OutputOrder) -> Vec<SerializableClient> {
+    let mut clients = vec![];
+    storage
+        .visit_clients(&mut |client| {
+            for row in SerializableClient::rows(client) {
+                clients.push((client.insertion_order, row));
+            }
+        })
+        .await;
+
+    // Stable sorts: the rows of a client stay sorted by currency
+    match order {
+        OutputOrder::ClientID => clients.sort_by_key(|(_, c)| c.client),
+        OutputOrder::Insertion => clients.sort_by_key(|(position, _)| *position),
+    };
+
+    clients.into_iter().map(|(_, client)| client).collect()
+}
+
+fn write_rows<T, W>(
+    rows: impl Iterator<Item = T>,
+    format: OutputFormat,
+    out: W,
+) -> Result<(), Error>
+where
+    T: Serialize,
+    W: io::Write,
+{
+    // Amounts are serialized as strings, so they keep the same representation as in the csv
+    match format {
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            for row in rows {
+                writer.serialize(row).map_err(|_| Error::FailedWriting)?;
+            }
+            writer.flush().map_err(|_| Error::FailedWriting)
+        }
+        OutputFormat::Json => {
+            let mut out = io::BufWriter::new(out);
+            serde_json::to_writer(&mut out, &rows.collect::<Vec<_>>())
+                .map_err(|_| Error::FailedWriting)?;
+            writeln!(out).map_err(|_| Error::FailedWriting)?;
+            out.flush().map_err(|_| Error::FailedWriting)
+        }
+        OutputFormat::Jsonl => {
+            let mut out = io::BufWriter::new(out);
+            for row in rows {
+                serde_json::to_writer(&mut out, &row).map_err(|_| Error::FailedWriting)?;
+                writeln!(out).map_err(|_| Error::FailedWriting)?;
+            }
+            out.flush().map_err(|_| Error::FailedWriting)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::policy::DisputePolicy,
+        fees::{Fee, FeeSchedule},
+        ledger::Ledger,
+        transaction::{ParsedTransaction, TransactionType},
+    };
+
+    async fn write_string(ledger: &Ledger, order: OutputOrder, format: OutputFormat) -> String {
+        let mut out = Vec::new();
+        write_to(ledger.storage(), order, format, &mut out)
+            .await
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    async fn ledger_with_clients(clients: &[ClientID]) -> Ledger {
+        let ledger = Ledger::new();
+
+        for (tx_id, client_id) in clients.iter().enumerate() {
+            ledger
+                .apply(ParsedTransaction::new(
+                    TransactionType::Deposit,
+                    *client_id,
+                    tx_id as u32,
+                    Some(Decimal::from(*client_id)),
+                ))
+                .await
+                .unwrap();
+        }
+
+        ledger
+    }
+
+    #[tokio::test]
+    async fn test_write_order() {
+        let ledger = ledger_with_clients(&[3, 1, 2]).await;
+
+        assert_eq!(
+            write_string(&ledger, OutputOrder::ClientID, OutputFormat::Csv).await,
+            "client,available,held,total,locked\n1,1,0.0000,1,false\n2,2,0.0000,2,false\n3,3,0.0000,3,false\n"
+        );
+        assert_eq!(
+            write_string(&ledger, OutputOrder::Insertion, OutputFormat::Csv).await,
+            "client,available,held,total,locked\n3,3,0.0000,3,false\n1,1,0.0000,1,false\n2,2,0.0000,2,false\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_json() {
+        let ledger = ledger_with_clients(&[2, 1]).await;
+
+        assert_eq!(
+            write_string(&ledger, OutputOrder::ClientID, OutputFormat::Json).await,
+            concat!(
+                r#"[{"client":1,"available":"1","held":"0.0000","total":"1","locked":false},"#,
+                r#"{"client":2,"available":"2","held":"0.0000","total":"2","locked":false}]"#,
+                "\n"
+            )
+        );
+        assert_eq!(
+            write_string(&ledger, OutputOrder::ClientID, OutputFormat::Jsonl).await,
+            concat!(
+                r#"{"client":1,"available":"1","held":"0.0000","total":"1","locked":false}"#,
+                "\n",
+                r#"{"client":2,"available":"2","held":"0.0000","total":"2","locked":false}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_deficits() {
+        let ledger = ledger_with_clients(&[1, 2])
+            .await
+            .with_dispute_policy(DisputePolicy::Full);
+        let tx = |tx_type, tx_id, amount: Option<u32>| {
+            ParsedTransaction::new(tx_type, 2, tx_id, amount.map(Decimal::from))
+        };
+        ledger
+            .apply(tx(TransactionType::Withdrawal, 2, Some(2)))
+            .await
+            .unwrap();
+        ledger
+            .apply(tx(TransactionType::Dispute, 1, None))
+            .await
+            .unwrap();
+
+        // The negative exposure shows in the regular output too
+        assert_eq!(
+            write_string(&ledger, OutputOrder::ClientID, OutputFormat::Csv).await,
+            "client,available,held,total,locked\n1,1,0.0000,1,false\n2,-2,2,0.0000,false\n"
+        );
+
+        let mut out = Vec::new();
+        write_deficits(ledger.storage(), OutputFormat::Csv, &mut out)
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,deficit,available,held,total,locked\n2,2,-2,2,0.0000,false\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_currencies() {
+        let ledger = ledger_with_clients(&[2, 1]).await;
+        for (tx_id, currency) in [(2, "USD"), (3, "EUR")] {
+            ledger
+                .apply(ParsedTransaction {
+                    currency: Some(currency.to_string()),
+                    ..ParsedTransaction::new(
+                        TransactionType::Deposit,
+                        2,
+                        tx_id,
+                        Some(Decimal::from(tx_id)),
+                    )
+                })
+                .await
+                .unwrap();
+        }
+
+        // One row per client and currency
+        assert_eq!(
+            write_string(&ledger, OutputOrder::Insertion, OutputFormat::Csv).await,
+            "client,currency,available,held,total,locked\n2,,2,0.0000,2,false\n2,EUR,3,0.0000,3,false\n2,USD,2,0.0000,2,false\n1,,1,0.0000,1,false\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_revenue() {
+        let ledger = Ledger::new().with_fees(
+            FeeSchedule::new().with_fee(TransactionType::Withdrawal, Fee::flat(Decimal::new(5, 1))),
+        );
+        let tx = |tx_type, client_id, tx_id, amount, currency: &str| ParsedTransaction {
+            currency: Some(currency.to_string()),
+            ..ParsedTransaction::new(tx_type, client_id, tx_id, Some(Decimal::from(amount)))
+        };
+        for tx in [
+            tx(TransactionType::Deposit, 1, 1, 3, "EUR"),
+            tx(TransactionType::Withdrawal, 1, 2, 2, "EUR"),
+            tx(TransactionType::Deposit, 2, 3, 2, "USD"),
+            tx(TransactionType::Fee, 2, 4, 2, "USD"),
+        ] {
+            ledger.apply(tx).await.unwrap();
+        }
 
-    let db_read = db.read().await;
-    let iter_clients = db_read.values();
+        // Fees go to the house, which is only in its own report
+        assert_eq!(
+            write_string(&ledger, OutputOrder::ClientID, OutputFormat::Csv).await,
+            "client,currency,available,held,total,locked
+1,EUR,0.5,0.0000,0.5,false
+2,USD,0.0000,0.0000,0.0000,false
+"
+        );
 
-    for c in iter_clients {
-        let client = c.read().await;
-        writer.serialize(&*client).ok();
+        let mut out = vec![];
+        write_revenue(ledger.storage(), OutputFormat::Csv, &mut out)
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "currency,revenue\nEUR,0.5\nUSD,2\n"
+        );
     }
 }